@@ -0,0 +1,115 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local};
+use serde::{Serialize, Deserialize};
+
+use super::taskmanager::Task;
+use super::backup;
+use super::crypto::{self, Cipher};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ArchivedTask {
+    pub project: String,
+    pub archived_at: DateTime<Local>,
+    pub task: Task,
+}
+
+/// Tasks moved out of the active list, kept in their own file next to the
+/// save file so that the save file doesn't grow forever.
+#[derive(Debug, Default)]
+pub struct Archive {
+    tasks: Vec<ArchivedTask>,
+    // whether there are changes that haven't been written yet
+    dirty: bool,
+}
+
+impl Archive {
+    /// 'taskman.json' is archived to 'taskman.archive.json'.
+    pub fn path_for(save_file: &Path) -> PathBuf {
+        let stem = save_file
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        save_file.with_file_name(format!("{}.archive.json", stem))
+    }
+
+    /// A missing archive file just means nothing was archived yet. An
+    /// encrypted one needs the cipher of the task file it belongs to.
+    pub fn load(path: &Path, cipher: Option<&Cipher>) -> Result<Archive, String> {
+        let content = match fs::read(path) {
+            Ok(c) => c,
+            Err(_) if !path.exists() => { return Ok(Archive::default()); },
+            Err(_) => { return Err(format!("could not read archive file '{}'...", path.display())); },
+        };
+        let content = match (cipher, crypto::is_encrypted(&content)) {
+            (Some(cipher), true) => {
+                // decrypting may switch the cipher over to the archive's salt
+                let mut cipher = Cipher::clone(cipher);
+                cipher.decrypt(&content)
+                    .map_err(|e| format!("can't open archive file '{}': {}...", path.display(), e))?
+            },
+            (None, true) => { return Err(format!("archive file '{}' is encrypted but the task file isn't...", path.display())); },
+            (_, false) => content,
+        };
+        let buffer = String::from_utf8_lossy(&content);
+        if buffer.trim().is_empty() {
+            return Ok(Archive::default());
+        }
+        Ok(Archive {
+            tasks: serde_json::from_str(&buffer)
+                .ok().ok_or(format!("couldn't deserialize archive file '{}'...", path.display()))?,
+            dirty: false,
+        })
+    }
+
+    pub fn save(&mut self, path: &Path, cipher: Option<&Cipher>) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.dirty { return Ok(()); }
+        let serialized = serde_json::to_string(&self.tasks)?;
+        let content = match cipher {
+            Some(cipher) => cipher.encrypt(serialized.as_bytes()).map_err(|e| e.to_string())?,
+            None => serialized.into_bytes(),
+        };
+
+        backup::write_atomic(path, &content)?;
+
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Has the archive written again on the next save, e.g. to encrypt it.
+    pub fn mark_changed(&mut self) {
+        self.dirty = true;
+    }
+
+    pub fn push(&mut self, project: &str, task: Task) {
+        self.tasks.push(ArchivedTask { project: project.to_owned(), archived_at: Local::now(), task });
+        self.dirty = true;
+    }
+
+    pub fn get(&self, id: u32) -> Option<&ArchivedTask> {
+        self.tasks.iter().find(|a| a.task.id() == id)
+    }
+
+    pub fn take(&mut self, id: u32) -> Option<ArchivedTask> {
+        let i = self.tasks.iter().position(|a| a.task.id() == id)?;
+        self.dirty = true;
+        Some(self.tasks.remove(i))
+    }
+
+    /// Archived tasks whose title, description or tags contain the query
+    /// (ignoring case), most recently archived first.
+    pub fn search(&self, query: &str) -> Vec<&ArchivedTask> {
+        let query = query.to_lowercase();
+        let mut res: Vec<&ArchivedTask> = self.tasks
+            .iter()
+            .filter(|a| a.task.title().to_lowercase().contains(&query)
+                || a.task.description().to_lowercase().contains(&query)
+                || a.task.tags().iter().any(|t| t.to_lowercase().contains(&query)))
+            .collect();
+        res.sort_by_key(|a| std::cmp::Reverse(a.archived_at));
+        res
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+
+/// How many backups are kept next to the save file, older ones get removed.
+pub const BACKUPS_KEPT: usize = 5;
+
+const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S%3f";
+
+#[derive(Debug, Clone)]
+pub struct Backup {
+    pub path: PathBuf,
+    pub created: DateTime<Local>,
+    pub size: u64,
+}
+
+/// Replaces the file's content without ever leaving a half written file
+/// behind: the data goes to a temporary file in the same directory, which
+/// is synced to disk and then renamed over the original.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let tmp = path.with_file_name(format!(".{}.tmp", name));
+
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp)?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    drop(f);
+
+    if let Err(e) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+
+    // make the rename itself durable, not every platform can open a directory
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        if let Ok(d) = File::open(dir) {
+            let _ = d.sync_all();
+        }
+    }
+    Ok(())
+}
+
+// 'taskman.json' is backed up to 'taskman.backup-<timestamp>.json'
+fn backup_prefix(save_file: &Path) -> String {
+    let stem = save_file
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    format!("{}.backup-", stem)
+}
+
+/// Writes the save file's content (as the caller wants it kept, e.g.
+/// encrypted) to a new timestamped backup and drops the backups beyond the
+/// `keep` most recent ones. Empty content has nothing worth keeping.
+pub fn create(save_file: &Path, content: &[u8], keep: usize) -> io::Result<()> {
+    if content.iter().all(|b| b.is_ascii_whitespace()) {
+        return Ok(());
+    }
+
+    let name = format!("{}{}.json", backup_prefix(save_file), Local::now().format(TIMESTAMP_FORMAT));
+    write_atomic(&save_file.with_file_name(name), content)?;
+
+    for old in list(save_file).iter().skip(keep) {
+        fs::remove_file(&old.path)?;
+    }
+    Ok(())
+}
+
+/// The backups of the save file, most recent first.
+pub fn list(save_file: &Path) -> Vec<Backup> {
+    let prefix = backup_prefix(save_file);
+    let dir = match save_file.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => { return Vec::new(); },
+    };
+
+    let mut backups: Vec<Backup> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().to_string_lossy().to_string();
+            let timestamp = name.strip_prefix(&prefix)?.strip_suffix(".json")?;
+            let created = NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok()?;
+            Some(Backup {
+                path: save_file.with_file_name(&name),
+                created: Local.from_local_datetime(&created).earliest()?,
+                size: e.metadata().map(|m| m.len()).unwrap_or(0),
+            })
+        })
+        .collect();
+    backups.sort_by_key(|b| std::cmp::Reverse(b.created));
+    backups
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn atomic_writes_replace_the_file_and_leave_nothing_behind() {
+        let dir = TestDir::new();
+        let path = dir.join("tasks.json");
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn only_the_most_recent_backups_are_kept() {
+        let dir = TestDir::new();
+        let path = dir.join("tasks.json");
+        for i in 0..4 {
+            create(&path, format!("version {}", i).as_bytes(), 3).unwrap();
+            // backups are told apart by the millisecond they were made in
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        create(&path, b"  \n", 3).unwrap();
+
+        let backups = list(&path);
+        let kept: Vec<Vec<u8>> = backups.iter().map(|b| fs::read(&b.path).unwrap()).collect();
+        assert_eq!(kept, vec![b"version 3".to_vec(), b"version 2".to_vec(), b"version 1".to_vec()]);
+        assert_eq!(backups[0].size, 9);
+        // other files' backups aren't mixed in
+        create(&dir.join("other.json"), b"other", 3).unwrap();
+        assert_eq!(list(&path).len(), 3);
+    }
+}
@@ -0,0 +1,162 @@
+use std::fmt::Display;
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+
+// encrypted files start with this, followed by the format version, the
+// salt the key was derived with and the nonce; all of it is authenticated
+// along with the encrypted content
+const MAGIC: &[u8] = b"TASKMAN-ENCRYPTED\n";
+const VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 1 + SALT_LEN;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CryptoError {
+    // or the file was modified, the two can't be told apart
+    WrongPassphrase,
+    Corrupt,
+    UnknownVersion(u8),
+    Failed(String),
+}
+
+impl Display for CryptoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CryptoError::WrongPassphrase => write!(f, "wrong passphrase (or the file was modified)"),
+            CryptoError::Corrupt => write!(f, "the file is damaged"),
+            CryptoError::UnknownVersion(v) => write!(f, "the file is encrypted with an unknown format ({}), it may need a newer taskman", v),
+            CryptoError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Whether the content is something `Cipher::encrypt` produced.
+pub fn is_encrypted(content: &[u8]) -> bool {
+    content.starts_with(MAGIC)
+}
+
+// the salt encrypted content was encrypted with
+fn salt(content: &[u8]) -> Result<[u8; SALT_LEN], CryptoError> {
+    if !is_encrypted(content) || content.len() < HEADER_LEN + NONCE_LEN {
+        return Err(CryptoError::Corrupt);
+    }
+    let version = content[MAGIC.len()];
+    if version != VERSION {
+        return Err(CryptoError::UnknownVersion(version));
+    }
+    let mut salt = [0u8; SALT_LEN];
+    salt.copy_from_slice(&content[MAGIC.len() + 1..HEADER_LEN]);
+    Ok(salt)
+}
+
+/// Encrypts and decrypts files with a key derived from a passphrase
+/// (Argon2id, then ChaCha20-Poly1305). The key is derived once per salt,
+/// every encryption gets a fresh nonce.
+#[derive(Clone)]
+pub struct Cipher {
+    passphrase: String,
+    salt: [u8; SALT_LEN],
+    key: [u8; 32],
+}
+
+impl Cipher {
+    /// A cipher with a new random salt.
+    pub fn new(passphrase: &str) -> Result<Cipher, CryptoError> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Cipher::with_salt(passphrase, salt)
+    }
+
+    /// A cipher for content encrypted earlier, it uses the same salt.
+    pub fn for_content(passphrase: &str, content: &[u8]) -> Result<Cipher, CryptoError> {
+        Cipher::with_salt(passphrase, salt(content)?)
+    }
+
+    fn with_salt(passphrase: &str, salt: [u8; SALT_LEN]) -> Result<Cipher, CryptoError> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| CryptoError::Failed(format!("could not derive the key: {}", e)))?;
+        Ok(Cipher { passphrase: passphrase.to_owned(), salt, key })
+    }
+
+    fn header(&self) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        header.push(VERSION);
+        header.extend_from_slice(&self.salt);
+        header
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let header = self.header();
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&self.key))
+            .encrypt(&nonce, Payload { msg: plaintext, aad: &header })
+            .map_err(|_| CryptoError::Failed("could not encrypt".to_string()))?;
+        let mut res = header;
+        res.extend_from_slice(&nonce);
+        res.extend_from_slice(&ciphertext);
+        Ok(res)
+    }
+
+    /// Decrypts what was encrypted with the same passphrase. Content that
+    /// was encrypted with another salt (e.g. by another taskman instance
+    /// after a passphrase change) has its key derived again, and the
+    /// cipher takes that salt on.
+    pub fn decrypt(&mut self, content: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let salt = salt(content)?;
+        if salt != self.salt {
+            *self = Cipher::with_salt(&self.passphrase, salt)?;
+        }
+        let nonce = Nonce::from_slice(&content[HEADER_LEN..HEADER_LEN + NONCE_LEN]);
+        ChaCha20Poly1305::new(Key::from_slice(&self.key))
+            .decrypt(nonce, Payload { msg: &content[HEADER_LEN + NONCE_LEN..], aad: &content[..HEADER_LEN] })
+            .map_err(|_| CryptoError::WrongPassphrase)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let content = Cipher::new("secret").unwrap().encrypt(b"tasks").unwrap();
+        assert!(is_encrypted(&content));
+        assert!(!is_encrypted(b"tasks"));
+        let mut cipher = Cipher::for_content("secret", &content).unwrap();
+        assert_eq!(cipher.decrypt(&content).unwrap(), b"tasks");
+    }
+
+    #[test]
+    fn a_wrong_passphrase_or_tampering_is_detected() {
+        let mut content = Cipher::new("secret").unwrap().encrypt(b"tasks").unwrap();
+        let mut wrong = Cipher::for_content("guess", &content).unwrap();
+        assert_eq!(wrong.decrypt(&content), Err(CryptoError::WrongPassphrase));
+
+        let mut cipher = Cipher::for_content("secret", &content).unwrap();
+        let last = content.len() - 1;
+        content[last] ^= 1;
+        assert_eq!(cipher.decrypt(&content), Err(CryptoError::WrongPassphrase));
+        assert_eq!(cipher.decrypt(&content[..HEADER_LEN]), Err(CryptoError::Corrupt));
+    }
+
+    #[test]
+    fn other_salts_and_versions() {
+        // e.g. the file was saved by another instance after a passphrase change
+        let content = Cipher::new("secret").unwrap().encrypt(b"tasks").unwrap();
+        let mut cipher = Cipher::new("secret").unwrap();
+        assert_eq!(cipher.decrypt(&content).unwrap(), b"tasks");
+
+        let mut newer = content.clone();
+        newer[MAGIC.len()] = VERSION + 1;
+        assert_eq!(cipher.decrypt(&newer), Err(CryptoError::UnknownVersion(VERSION + 1)));
+    }
+}
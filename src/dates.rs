@@ -0,0 +1,97 @@
+use chrono::{Datelike, Days, Duration, Local, Months, NaiveDate, Weekday};
+
+#[derive(Debug)]
+pub struct ParseDateError;
+
+pub fn today() -> NaiveDate {
+    Local::now().date_naive()
+}
+
+/// Parses a date given either in ISO format (2023-06-01) or as a phrase
+/// relative to `today`:
+/// * today, tomorrow, yesterday
+/// * a weekday (fri, friday, ...), meaning the next one after today
+/// * an offset like +3d, +2w or +1m
+pub fn parse_date(s: &str, today: NaiveDate) -> Result<NaiveDate, ParseDateError> {
+    let s = s.trim().to_lowercase();
+
+    if let Ok(date) = NaiveDate::parse_from_str(&s, "%Y-%m-%d") {
+        return Ok(date);
+    }
+
+    match s.as_str() {
+        "today" => return Ok(today),
+        "tomorrow" => return Ok(today + Duration::days(1)),
+        "yesterday" => return Ok(today - Duration::days(1)),
+        _ => {},
+    };
+
+    if let Ok(weekday) = s.parse::<Weekday>() {
+        let mut days_ahead = (weekday.num_days_from_monday() + 7
+            - today.weekday().num_days_from_monday()) % 7;
+        if days_ahead == 0 { days_ahead = 7; }
+        return Ok(today + Duration::days(days_ahead as i64));
+    }
+
+    if let Some(offset) = s.strip_prefix('+') {
+        if offset.len() < 2 { return Err(ParseDateError); }
+        let (amount, unit) = offset.split_at(offset.len() - 1);
+        let amount = amount.parse::<u32>().ok().ok_or(ParseDateError)?;
+        return match unit {
+            "d" => today.checked_add_days(Days::new(amount as u64)).ok_or(ParseDateError),
+            "w" => today.checked_add_days(Days::new(amount as u64 * 7)).ok_or(ParseDateError),
+            "m" => today.checked_add_months(Months::new(amount)).ok_or(ParseDateError),
+            _ => Err(ParseDateError),
+        };
+    }
+
+    Err(ParseDateError)
+}
+
+/// Formats a duration as e.g. '2h 05m', or '12m' when under an hour.
+pub fn format_duration(duration: Duration) -> String {
+    let minutes = duration.num_minutes().max(0);
+    if minutes >= 60 {
+        format!("{}h {:02}m", minutes / 60, minutes % 60)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn dates_can_be_given_relative_to_today() {
+        // a wednesday
+        let today = date("2023-05-31");
+        assert_eq!(parse_date("2023-06-01", today).unwrap(), date("2023-06-01"));
+        assert_eq!(parse_date("Tomorrow", today).unwrap(), date("2023-06-01"));
+        assert_eq!(parse_date("yesterday", today).unwrap(), date("2023-05-30"));
+        assert_eq!(parse_date("fri", today).unwrap(), date("2023-06-02"));
+        assert_eq!(parse_date("wednesday", today).unwrap(), date("2023-06-07"));
+        assert_eq!(parse_date("+3d", today).unwrap(), date("2023-06-03"));
+        assert_eq!(parse_date("+2w", today).unwrap(), date("2023-06-14"));
+        // the end of the month is clamped
+        assert_eq!(parse_date("+1m", today).unwrap(), date("2023-06-30"));
+    }
+
+    #[test]
+    fn offsets_past_the_last_date_are_rejected() {
+        let today = date("2023-05-31");
+        assert!(parse_date("+99999999d", today).is_err());
+        assert!(parse_date("+4294967295w", today).is_err());
+        assert!(parse_date("+4294967295m", today).is_err());
+        assert!(parse_date("+99999999999d", today).is_err());
+        assert!(parse_date("+d", today).is_err());
+        assert!(parse_date("+3y", today).is_err());
+        assert!(parse_date("someday", today).is_err());
+    }
+}
@@ -0,0 +1,58 @@
+use std::{
+    io,
+    path::Path,
+    sync::mpsc::{self, Sender},
+    thread,
+};
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+
+/// What the TUI's main loop waits on.
+pub enum Event {
+    // a line typed at the prompt
+    Input(String),
+    // stdin was closed
+    Closed,
+    // the watched file was written, possibly by taskman itself
+    FileChanged,
+}
+
+/// Reads stdin line by line on its own thread. After each line it waits
+/// until it's sent a go-ahead on the returned channel, so that handling the
+/// line can read from the terminal itself (e.g. to ask for a passphrase).
+pub fn read_input(tx: Sender<Event>) -> Sender<()> {
+    let (resume, resumed) = mpsc::channel::<()>();
+    thread::spawn(move || {
+        loop {
+            let mut input = String::new();
+            let event = match io::stdin().read_line(&mut input) {
+                Ok(0) | Err(_) => Event::Closed,
+                Ok(_) => Event::Input(input.trim_end().to_owned()),
+            };
+            let closed = matches!(event, Event::Closed);
+            if tx.send(event).is_err() || closed || resumed.recv().is_err() { break; }
+        }
+    });
+    resume
+}
+
+/// Watches the file for changes until the returned watcher is dropped.
+/// Its directory is watched rather than the file itself, since saving
+/// replaces the file instead of writing to it.
+pub fn watch(file: &Path, tx: Sender<Event>) -> notify::Result<RecommendedWatcher> {
+    let name = file.file_name().map(|n| n.to_owned());
+    let dir = match file.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d.to_owned(),
+        _ => Path::new(".").to_owned(),
+    };
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if let Ok(event) = res {
+            let ours = event.paths.iter().any(|p| p.file_name() == name.as_deref());
+            if ours && (event.kind.is_create() || event.kind.is_modify()) {
+                let _ = tx.send(Event::FileChanged);
+            }
+        }
+    })?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
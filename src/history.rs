@@ -0,0 +1,81 @@
+use std::collections::VecDeque;
+
+use super::taskmanager::Task;
+use super::storage::same;
+
+/// How many operations can be undone.
+pub const OPERATIONS_KEPT: usize = 100;
+
+/// What a single command did to a project's tasks, as the task list before
+/// and after it.
+#[derive(Debug, Clone)]
+pub struct Operation {
+    // what was done, e.g. the command line
+    pub label: String,
+    pub project: String,
+    pub before: Vec<Task>,
+    pub after: Vec<Task>,
+}
+
+/// Undo and redo stacks of operations, the oldest ones are dropped once
+/// there are more than `capacity` of them.
+#[derive(Debug)]
+pub struct History {
+    undo: VecDeque<Operation>,
+    redo: Vec<Operation>,
+    capacity: usize,
+    // label, project and tasks of the operation in progress
+    started: Option<(String, String, Vec<Task>)>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History { undo: VecDeque::new(), redo: Vec::new(), capacity, started: None }
+    }
+
+    /// Remembers the tasks as they are before an operation.
+    pub fn begin(&mut self, label: &str, project: &str, tasks: &[Task]) {
+        self.started = Some((label.to_owned(), project.to_owned(), tasks.to_vec()));
+    }
+
+    /// Records the operation begun last, unless it left the tasks alone
+    /// (e.g. because it failed). Recording drops what could be redone.
+    pub fn end(&mut self, project: &str, tasks: &[Task]) {
+        let (label, started_in, before) = match self.started.take() {
+            Some(started) => started,
+            None => { return; },
+        };
+        if started_in != project || same(before.as_slice(), tasks) { return; }
+        if self.undo.len() >= self.capacity {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(Operation { label, project: started_in, before, after: tasks.to_vec() });
+        self.redo.clear();
+    }
+
+    pub fn last_undo(&self) -> Option<&Operation> { self.undo.back() }
+
+    pub fn last_redo(&self) -> Option<&Operation> { self.redo.last() }
+
+    /// Moves the last operation over to the redo stack.
+    pub fn undo(&mut self) -> Option<Operation> {
+        let op = self.undo.pop_back()?;
+        self.redo.push(op.clone());
+        Some(op)
+    }
+
+    /// Moves the last undone operation back to the undo stack.
+    pub fn redo(&mut self) -> Option<Operation> {
+        let op = self.redo.pop()?;
+        self.undo.push_back(op.clone());
+        Some(op)
+    }
+
+    /// Forgets everything, for when the tasks change in ways operations
+    /// can't be undone across.
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.started = None;
+    }
+}
@@ -0,0 +1,456 @@
+use std::{
+    error::Error,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local};
+use serde::{Serialize, Deserialize};
+
+use super::taskmanager::{Autosave, Task, DEFAULT_PROJECT};
+use super::workflow::Workflow;
+use super::scale::PriorityScale;
+use super::storage::{same, Change, Project, SaveData, SaveDataRef, Storage, TaskVersion};
+use super::backup;
+use super::lock::FileLock;
+
+/// How many events are appended before the state is written to the
+/// snapshot again, so loading doesn't replay the whole journal.
+pub const COMPACT_EVERY: usize = 500;
+
+/// A single change to the stored data.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    // a new or modified task, stored whole
+    TaskSaved { project: String, task: Box<Task> },
+    TaskRemoved { id: u32 },
+    // everything besides the tasks, projects are listed in their order
+    Settings {
+        next_id: u32,
+        workflow: Workflow,
+        priority_scale: PriorityScale,
+        auto_archive_days: Option<u32>,
+        autosave: Autosave,
+        active_project: String,
+        projects: Vec<String>,
+    },
+}
+
+/// One line of the journal.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Entry {
+    pub at: DateTime<Local>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    // how far into the journal (in bytes) the data goes
+    offset: u64,
+    data: SaveData,
+}
+
+#[derive(Serialize)]
+struct SnapshotRef<'t> {
+    offset: u64,
+    data: SaveDataRef<'t>,
+}
+
+fn settings(data: &SaveData) -> Event {
+    Event::Settings {
+        next_id: data.next_id,
+        workflow: data.workflow.clone(),
+        priority_scale: data.priority_scale.clone(),
+        auto_archive_days: data.auto_archive_days,
+        autosave: data.autosave,
+        active_project: data.active_project.clone().unwrap_or(DEFAULT_PROJECT.to_owned()),
+        projects: data.projects.iter().map(|p| p.name.clone()).collect(),
+    }
+}
+
+fn apply(data: &mut SaveData, event: Event) {
+    match event {
+        Event::TaskSaved { project, task } => {
+            let task = *task;
+            data.next_id = data.next_id.max(task.id() + 1);
+            // updated tasks keep their place, unless they changed project
+            for p in data.projects.iter_mut() {
+                if let Some(i) = p.tasks.iter().position(|t| t.id() == task.id()) {
+                    if p.name == project {
+                        p.tasks[i] = task;
+                        return;
+                    }
+                    p.tasks.remove(i);
+                }
+            }
+            match data.projects.iter_mut().find(|p| p.name == project) {
+                Some(p) => { p.tasks.push(task); },
+                None => { data.projects.push(Project { name: project, tasks: vec![task] }); },
+            };
+        },
+        Event::TaskRemoved { id } => {
+            for p in data.projects.iter_mut() {
+                p.tasks.retain(|t| t.id() != id);
+            }
+        },
+        Event::Settings { next_id, workflow, priority_scale, auto_archive_days, autosave, active_project, projects } => {
+            data.next_id = data.next_id.max(next_id);
+            data.workflow = workflow;
+            data.priority_scale = priority_scale;
+            data.auto_archive_days = auto_archive_days;
+            data.autosave = autosave;
+            data.active_project = Some(active_project);
+            // projects that still hold tasks are kept even if not listed
+            let mut ordered = Vec::<Project>::new();
+            for name in projects {
+                match data.projects.iter().position(|p| p.name == name) {
+                    Some(i) => { ordered.push(data.projects.remove(i)); },
+                    None => { ordered.push(Project { name, tasks: Vec::new() }); },
+                };
+            }
+            ordered.extend(data.projects.drain(..).filter(|p| !p.tasks.is_empty()));
+            data.projects = ordered;
+        },
+    };
+}
+
+/// Keeps every change as an event appended to a journal file
+/// ('taskman.journal', one JSON object per line), the stored data is what
+/// replaying them gives. A snapshot of it ('taskman.snapshot.json') is
+/// written every now and then, loading only replays what came after it.
+/// The journal itself is never truncated or rotated: it holds the whole
+/// history of every task (see `task_history`) and grows without limit,
+/// only the time loading takes is bounded by the snapshots.
+pub struct JournalStorage {
+    path: PathBuf,
+    // the stored data as of the end of the journal
+    state: SaveData,
+    // the journal's length as of the last load or append
+    len: Option<u64>,
+    // events appended since the last snapshot
+    since_snapshot: usize,
+    _lock: FileLock,
+}
+
+impl JournalStorage {
+    pub fn open(path: &Path) -> Result<JournalStorage, String> {
+        Ok(JournalStorage {
+            path: path.to_owned(),
+            state: SaveData::default(),
+            len: None,
+            since_snapshot: 0,
+            _lock: FileLock::acquire(path)?,
+        })
+    }
+
+    /// 'taskman.journal' is snapshotted to 'taskman.snapshot.json'.
+    pub fn snapshot_path(journal: &Path) -> PathBuf {
+        let stem = journal
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        journal.with_file_name(format!("{}.snapshot.json", stem))
+    }
+
+    // the journal's entries from `offset` on, a last line that's cut off
+    // (e.g. by a crash while appending) is skipped with a warning
+    fn read_entries(&self, offset: u64, warnings: &mut Vec<String>) -> Result<Vec<Entry>, String> {
+        let content = match fs::read(&self.path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => { return Err(format!("could not read journal '{}': {}...", self.path.display(), e)); },
+        };
+        let rest = content
+            .get(offset as usize..)
+            .ok_or(format!("journal '{}' is shorter than its snapshot, delete '{}' to replay it all...",
+                self.path.display(), JournalStorage::snapshot_path(&self.path).display()))?;
+        let rest = String::from_utf8_lossy(rest);
+        let lines: Vec<&str> = rest.lines().collect();
+        let first_line = content[..offset as usize].iter().filter(|b| **b == b'\n').count() + 1;
+
+        let mut entries = Vec::<Entry>::new();
+        for (i, line) in lines.iter().enumerate() {
+            if line.trim().is_empty() { continue; }
+            match serde_json::from_str::<Entry>(line) {
+                Ok(entry) => { entries.push(entry); },
+                Err(_) if i + 1 == lines.len() && !rest.ends_with('\n') => {
+                    warnings.push(format!("skipped the incomplete last line of journal '{}'...", self.path.display()));
+                },
+                Err(e) => {
+                    return Err(format!("couldn't load journal '{}': line {}: {}...", self.path.display(), first_line + i, e));
+                },
+            };
+        }
+        Ok(entries)
+    }
+
+    fn drop_incomplete_line(&self) -> io::Result<()> {
+        let content = fs::read(&self.path)?;
+        let complete = content.iter().rposition(|b| *b == b'\n').map(|i| i + 1).unwrap_or(0);
+        OpenOptions::new().write(true).open(&self.path)?.set_len(complete as u64)
+    }
+
+    fn append(&mut self, events: Vec<Event>) -> Result<(), Box<dyn Error>> {
+        if events.is_empty() { return Ok(()); }
+        // what was appended elsewhere isn't in the state, that still has to
+        // be reloaded or merged (and can't go into a snapshot until then)
+        let external = self.changed_externally();
+        let at = Local::now();
+        let mut lines = String::new();
+        for event in events.iter() {
+            lines.push_str(&serde_json::to_string(&Entry { at, event: event.clone() })?);
+            lines.push('\n');
+        }
+        let mut f = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        f.write_all(lines.as_bytes())?;
+        f.sync_data()?;
+        if !external {
+            self.len = Some(f.metadata()?.len());
+        }
+
+        self.since_snapshot += events.len();
+        for event in events {
+            apply(&mut self.state, event);
+        }
+        if self.since_snapshot >= COMPACT_EVERY && !external {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the current state to the snapshot.
+    pub fn compact(&mut self) -> Result<(), Box<dyn Error>> {
+        let snapshot = SnapshotRef { offset: self.len.unwrap_or(0), data: self.state.borrowed() };
+        backup::write_atomic(&JournalStorage::snapshot_path(&self.path), serde_json::to_string(&snapshot)?.as_bytes())?;
+        self.since_snapshot = 0;
+        Ok(())
+    }
+}
+
+impl Storage for JournalStorage {
+    fn load(&mut self) -> Result<(SaveData, Vec<String>), String> {
+        let snapshot_path = JournalStorage::snapshot_path(&self.path);
+        let snapshot = match fs::read_to_string(&snapshot_path) {
+            Ok(content) => serde_json::from_str::<Snapshot>(&content)
+                .map_err(|e| format!("couldn't load snapshot '{}': {}, delete it to replay the whole journal...",
+                    snapshot_path.display(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Snapshot { offset: 0, data: SaveData::default() },
+            Err(e) => { return Err(format!("could not read snapshot '{}': {}...", snapshot_path.display(), e)); },
+        };
+
+        let mut warnings = Vec::new();
+        let entries = self.read_entries(snapshot.offset, &mut warnings)?;
+        // appending after a cut off line would glue the next entry to it
+        if !warnings.is_empty() {
+            self.drop_incomplete_line()
+                .map_err(|e| format!("could not repair journal '{}': {}...", self.path.display(), e))?;
+        }
+        let mut data = snapshot.data;
+        self.since_snapshot = entries.len();
+        for entry in entries {
+            apply(&mut data, entry.event);
+        }
+        self.state = data.clone();
+        self.len = Some(fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0));
+        Ok((data, warnings))
+    }
+
+    // only what differs from the stored data is appended
+    fn save(&mut self, data: &SaveDataRef) -> Result<(), Box<dyn Error>> {
+        // overwriting what was appended elsewhere has to know what's there now
+        if self.changed_externally() {
+            self.load()?;
+        }
+        let mut events = Vec::<Event>::new();
+        for p in data.projects.iter() {
+            for t in p.tasks.iter() {
+                let stored = self.state.projects
+                    .iter()
+                    .find_map(|sp| sp.tasks.iter().find(|st| st.id() == t.id()).map(|st| (sp, st)));
+                let unchanged = stored.map(|(sp, st)| sp.name == p.name && same(st, t)).unwrap_or(false);
+                if !unchanged {
+                    events.push(Event::TaskSaved { project: p.name.to_owned(), task: Box::new(t.clone()) });
+                }
+            }
+        }
+        for sp in self.state.projects.iter() {
+            for st in sp.tasks.iter() {
+                if !data.projects.iter().any(|p| p.tasks.iter().any(|t| t.id() == st.id())) {
+                    events.push(Event::TaskRemoved { id: st.id() });
+                }
+            }
+        }
+        let new_settings = Event::Settings {
+            next_id: data.next_id,
+            workflow: data.workflow.clone(),
+            priority_scale: data.priority_scale.clone(),
+            auto_archive_days: data.auto_archive_days,
+            autosave: data.autosave,
+            active_project: data.active_project.to_owned(),
+            projects: data.projects.iter().map(|p| p.name.to_owned()).collect(),
+        };
+        if !same(&new_settings, &settings(&self.state)) {
+            events.push(new_settings);
+        }
+        self.append(events)
+    }
+
+    fn record(&mut self, change: Change) -> Result<(), Box<dyn Error>> {
+        let event = match change {
+            Change::TaskSaved { project, task } => Event::TaskSaved { project: project.to_owned(), task: Box::new(task.clone()) },
+            Change::TaskRemoved(id) => Event::TaskRemoved { id },
+        };
+        self.append(vec![event])
+    }
+
+    fn path(&self) -> Option<&Path> { Some(&self.path) }
+
+    // the journal only grows, anything else appending to it changes its
+    // length (a journal that doesn't exist yet is empty)
+    fn changed_externally(&self) -> bool {
+        match self.len {
+            Some(len) => fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0) != len,
+            None => false,
+        }
+    }
+
+    fn base(&self) -> Option<SaveData> { Some(self.state.clone()) }
+
+    fn task_history(&self, id: u32) -> Result<Vec<TaskVersion>, String> {
+        let mut warnings = Vec::new();
+        Ok(self.read_entries(0, &mut warnings)?
+            .into_iter()
+            .filter_map(|entry| match entry.event {
+                Event::TaskSaved { task, .. } if task.id() == id => Some(TaskVersion { at: entry.at, task: Some(*task) }),
+                Event::TaskRemoved { id: removed } if removed == id => Some(TaskVersion { at: entry.at, task: None }),
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use crate::taskmanager::{Priority, Status};
+
+    fn task(id: u32, title: &str) -> Task {
+        Task::new(id, &format!("a{:03}", id), title, "", Priority::new(0), Status::new("ToDo"))
+    }
+
+    fn data(tasks: Vec<Task>) -> SaveData {
+        SaveData {
+            active_project: Some(DEFAULT_PROJECT.to_owned()),
+            projects: vec![Project { name: DEFAULT_PROJECT.to_owned(), tasks }],
+            ..Default::default()
+        }
+    }
+
+    fn titles(data: &SaveData) -> Vec<&str> {
+        data.projects.iter().flat_map(|p| p.tasks.iter().map(|t| t.title())).collect()
+    }
+
+    #[test]
+    fn loading_replays_the_journal() {
+        let dir = TestDir::new();
+        let path = dir.join("tasks.journal");
+        let mut storage = JournalStorage::open(&path).unwrap();
+        storage.load().unwrap();
+        storage.save(&data(vec![task(0, "a"), task(1, "b")]).borrowed()).unwrap();
+        storage.record(Change::TaskSaved { project: "work", task: &task(0, "a2") }).unwrap();
+        storage.record(Change::TaskRemoved(1)).unwrap();
+        drop(storage);
+
+        let mut storage = JournalStorage::open(&path).unwrap();
+        let (loaded, warnings) = storage.load().unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(titles(&loaded), vec!["a2"]);
+        assert_eq!(loaded.projects.iter().find(|p| p.name == "work").unwrap().tasks.len(), 1);
+        assert_eq!(loaded.next_id, 2);
+        assert_eq!(storage.task_history(0).unwrap().len(), 2);
+        // saving what was loaded appends nothing
+        let len = fs::metadata(&path).unwrap().len();
+        storage.save(&loaded.borrowed()).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    }
+
+    #[test]
+    fn saving_over_external_appends_settles_them() {
+        let dir = TestDir::new();
+        let path = dir.join("tasks.journal");
+        let mut storage = JournalStorage::open(&path).unwrap();
+        storage.load().unwrap();
+        storage.save(&data(vec![task(0, "a")]).borrowed()).unwrap();
+        // another program appends a task
+        let entry = Entry { at: Local::now(), event: Event::TaskSaved { project: DEFAULT_PROJECT.to_owned(), task: Box::new(task(1, "b")) } };
+        let line = format!("{}\n", serde_json::to_string(&entry).unwrap());
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(line.as_bytes()).unwrap();
+        assert!(storage.changed_externally());
+
+        // single changes don't hide it
+        storage.record(Change::TaskSaved { project: DEFAULT_PROJECT, task: &task(2, "c") }).unwrap();
+        assert!(storage.changed_externally());
+        // overwriting replaces it, b is removed again
+        storage.save(&data(vec![task(0, "a2"), task(2, "c")]).borrowed()).unwrap();
+        assert!(!storage.changed_externally());
+        let (loaded, _) = storage.load().unwrap();
+        assert_eq!(titles(&loaded), vec!["a2", "c"]);
+    }
+
+    #[test]
+    fn a_cut_off_last_line_is_dropped() {
+        let dir = TestDir::new();
+        let path = dir.join("tasks.journal");
+        let mut storage = JournalStorage::open(&path).unwrap();
+        storage.load().unwrap();
+        storage.save(&data(vec![task(0, "a")]).borrowed()).unwrap();
+        let complete = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(br#"{"at":"2024-"#).unwrap();
+
+        let (loaded, warnings) = storage.load().unwrap();
+        assert_eq!(titles(&loaded), vec!["a"]);
+        assert!(warnings[0].contains("incomplete last line"));
+        assert_eq!(fs::metadata(&path).unwrap().len(), complete);
+        // what's appended next starts on a line of its own
+        storage.record(Change::TaskSaved { project: DEFAULT_PROJECT, task: &task(1, "b") }).unwrap();
+        let (loaded, warnings) = storage.load().unwrap();
+        assert_eq!(titles(&loaded), vec!["a", "b"]);
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn loading_replays_only_what_came_after_the_snapshot() {
+        let dir = TestDir::new();
+        let path = dir.join("tasks.journal");
+        let mut storage = JournalStorage::open(&path).unwrap();
+        storage.load().unwrap();
+        storage.save(&data(vec![task(0, "a")]).borrowed()).unwrap();
+        storage.compact().unwrap();
+        let offset = fs::metadata(&path).unwrap().len();
+        storage.record(Change::TaskSaved { project: DEFAULT_PROJECT, task: &task(1, "b") }).unwrap();
+
+        let snapshot: serde_json::Value = serde_json::from_str(
+            &fs::read_to_string(JournalStorage::snapshot_path(&path)).unwrap()).unwrap();
+        assert_eq!(snapshot["offset"], offset);
+        let (loaded, _) = storage.load().unwrap();
+        assert_eq!(titles(&loaded), vec!["a", "b"]);
+
+        // the snapshot stands in for the entries before its offset
+        let content = fs::read(&path).unwrap();
+        let mut replaced = b" ".repeat(offset as usize - 1);
+        replaced.push(b'\n');
+        replaced.extend_from_slice(&content[offset as usize..]);
+        fs::write(&path, &replaced).unwrap();
+        let (loaded, _) = storage.load().unwrap();
+        assert_eq!(titles(&loaded), vec!["a", "b"]);
+
+        fs::write(&path, b"").unwrap();
+        assert!(storage.load().err().unwrap().contains("shorter than its snapshot"));
+    }
+}
@@ -0,0 +1,67 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+/// An advisory lock held on a sidecar file ('taskman.json.lock') for as
+/// long as it lives. The save file itself can't be locked since saving
+/// replaces it with a new file.
+#[derive(Debug)]
+pub struct FileLock {
+    file: File,
+}
+
+impl FileLock {
+    pub fn path_for(locked: &Path) -> PathBuf {
+        let name = locked
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        locked.with_file_name(format!("{}.lock", name))
+    }
+
+    /// Fails if another process (e.g. another taskman instance) holds the
+    /// lock already.
+    pub fn acquire(locked: &Path) -> Result<FileLock, String> {
+        let path = FileLock::path_for(locked);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| format!("could not open lock file '{}': {}...", path.display(), e))?;
+
+        match file.try_lock() {
+            Ok(_) => { },
+            Err(TryLockError::WouldBlock) => {
+                // the holder writes its pid into the file
+                let mut holder = String::new();
+                let _ = file.read_to_string(&mut holder);
+                return Err(format!("'{}' is already open in another taskman instance{}...",
+                    locked.display(),
+                    match holder.trim() {
+                        "" => String::new(),
+                        pid => format!(" (pid {})", pid),
+                    }));
+            },
+            Err(TryLockError::Error(e)) => {
+                return Err(format!("could not lock '{}': {}...", path.display(), e));
+            },
+        };
+
+        let _ = file.set_len(0);
+        let _ = file.rewind();
+        let _ = write!(file, "{}", process::id());
+        Ok(FileLock { file })
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
@@ -1,27 +1,190 @@
 mod taskmanager;
 mod tasktui;
 mod queue;
+mod dates;
+mod recurrence;
+mod workflow;
+mod scale;
+mod archive;
+mod backup;
+mod schema;
+mod storage;
+mod sqlite;
+mod lock;
+mod merge;
+mod events;
+mod history;
+mod journal;
+mod sync;
+mod crypto;
+#[cfg(test)]
+mod testdir;
 
 use tasktui::TUI;
+use storage::{self as store, Storage, JsonStorage, MemoryStorage};
+use sqlite::SqliteStorage;
+use journal::JournalStorage;
 
 use std::{
     path::{Path, PathBuf},
     env,
+    process,
 };
 
 use dirs::home_dir;
 
-#[allow(unused_assignments)]
+static USAGE: &str = "usage: taskman [--storage json|sqlite|journal|memory] [--encrypt] [--import <json_file>] [file]
+       taskman merge <base> <ours> <theirs>   (merges task files into <ours>, e.g. as a git merge driver)";
+
+// wrong passphrases are asked for again this many times
+const PASSPHRASE_ATTEMPTS: usize = 3;
+
+// asks for the passphrase of an encrypted JSON file, or for a new one if
+// the file should become encrypted; the file stays as it is otherwise
+fn unlock(storage: &mut JsonStorage, path: &Path, encrypt: bool) -> Result<(), String> {
+    if storage.is_encrypted() {
+        let mut res = Err("no passphrase entered...".to_string());
+        for attempt in 1..=PASSPHRASE_ATTEMPTS {
+            let passphrase = rpassword::prompt_password(format!("passphrase for '{}': ", path.display()))
+                .map_err(|e| format!("could not read the passphrase: {}...", e))?;
+            res = storage.unlock(&passphrase);
+            match &res {
+                Ok(_) => { break; },
+                // the last error is left for the caller
+                Err(e) if attempt < PASSPHRASE_ATTEMPTS => { eprintln!("{}", e); },
+                Err(_) => { },
+            };
+        }
+        return res;
+    }
+    if encrypt {
+        let passphrase = rpassword::prompt_password(format!("new passphrase for '{}': ", path.display()))
+            .map_err(|e| format!("could not read the passphrase: {}...", e))?;
+        let confirmed = rpassword::prompt_password("repeat it: ")
+            .map_err(|e| format!("could not read the passphrase: {}...", e))?;
+        if passphrase.is_empty() || passphrase != confirmed {
+            return Err("the passphrases are empty or don't match...".to_string());
+        }
+        storage.set_passphrase(Some(&passphrase))?;
+    }
+    Ok(())
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let mut home_binding: PathBuf = PathBuf::new();
-    let save_file: &Path = match args.get(1) {
-        Some(arg) => { Path::new(arg) },
-        None => {
-            home_binding = home_dir().unwrap().join("taskman.json");
-            Path::new(home_binding.as_path())
+    let mut args = env::args().skip(1).peekable();
+
+    // merging task files writes the result over <ours> and exits, failing
+    // if there are conflicts left to resolve (they're marked on the tasks)
+    if args.peek().map(|a| a == "merge").unwrap_or(false) {
+        let files: Vec<PathBuf> = args.skip(1).map(PathBuf::from).collect();
+        if files.len() != 3 {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+        match merge::merge_files(&files[0], &files[1], &files[2]) {
+            Ok(report) if report.conflicts.is_empty() => { },
+            Ok(report) => {
+                for c in report.conflicts.iter() {
+                    eprintln!("conflict on task {} ({}), resolve it in taskman", c.id, c.fields.join(", "));
+                }
+                process::exit(1);
+            },
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(2);
+            },
+        };
+        return;
+    }
+    let mut backend = String::from("json");
+    let mut file: Option<PathBuf> = None;
+    let mut import: Option<PathBuf> = None;
+    let mut encrypt = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--storage" => {
+                backend = args.next().unwrap_or_else(|| {
+                    eprintln!("{}", USAGE);
+                    process::exit(2);
+                });
+            },
+            "--import" => {
+                import = Some(args.next().map(PathBuf::from).unwrap_or_else(|| {
+                    eprintln!("{}", USAGE);
+                    process::exit(2);
+                }));
+            },
+            "--encrypt" => { encrypt = true; },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            },
+            _ if file.is_none() => { file = Some(PathBuf::from(arg)); },
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            },
+        };
+    }
+    let default_file = |name: &str| home_dir().unwrap().join(name);
+    if encrypt && backend != "json" {
+        eprintln!("only JSON task files can be encrypted\n{}", USAGE);
+        process::exit(2);
+    }
+
+    let mut storage: Box<dyn Storage> = match backend.as_str() {
+        "json" => {
+            let path = file.unwrap_or_else(|| default_file("taskman.json"));
+            let opened = JsonStorage::open(&path)
+                .and_then(|mut s| unlock(&mut s, &path, encrypt).map(|_| s));
+            match opened {
+                Ok(s) => Box::new(s),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                },
+            }
+        },
+        "sqlite" => {
+            let path = file.unwrap_or_else(|| default_file("taskman.db"));
+            match SqliteStorage::open(Path::new(&path)) {
+                Ok(s) => Box::new(s),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                },
+            }
+        },
+        "journal" => {
+            match JournalStorage::open(&file.unwrap_or_else(|| default_file("taskman.journal"))) {
+                Ok(s) => Box::new(s),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                },
+            }
+        },
+        "memory" => Box::new(MemoryStorage::default()),
+        _ => {
+            eprintln!("unknown storage '{}'\n{}", backend, USAGE);
+            process::exit(2);
         },
     };
-                
-    TUI::new(Box::new(save_file)).run();
+
+    // importing copies a JSON task file into the chosen storage and exits
+    if let Some(json) = import {
+        let imported = JsonStorage::open(&json)
+            .and_then(|mut from| unlock(&mut from, &json, false).map(|_| from))
+            .and_then(|mut from| store::import(&mut from, storage.as_mut()));
+        match imported {
+            Ok(n) => { println!("imported {} tasks from '{}'", n, json.display()); },
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            },
+        };
+        return;
+    }
+
+    TUI::new(storage).run();
 }
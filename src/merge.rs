@@ -0,0 +1,494 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use chrono::DateTime;
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
+
+use super::storage::{same, Project, SaveData};
+use super::taskmanager::{find_cycle, Task};
+use super::schema;
+use super::backup;
+use super::crypto;
+
+// the value a `FieldConflict` on the whole task has on each side
+pub const CHANGED: &str = "changed";
+pub const REMOVED: &str = "removed";
+
+/// A field both sides of a merge set to different values, kept on the
+/// merged task (which has our value) until it's resolved. The field is
+/// "task" if one side removed the task while the other changed it, the
+/// values are then "changed" and "removed".
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FieldConflict {
+    pub field: String,
+    pub ours: Value,
+    pub theirs: Value,
+}
+
+impl FieldConflict {
+    pub fn removal(ours_removed: bool) -> FieldConflict {
+        let (ours, theirs) = if ours_removed { (REMOVED, CHANGED) } else { (CHANGED, REMOVED) };
+        FieldConflict { field: "task".to_string(), ours: Value::from(ours), theirs: Value::from(theirs) }
+    }
+}
+
+/// A side of a merge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Ours,
+    Theirs,
+}
+
+/// A task both sides changed in ways that couldn't be merged (or one side
+/// changed and the other removed), its conflicting fields are marked on
+/// the merged task.
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub id: u32,
+    pub ours: Option<Task>,
+    pub theirs: Option<Task>,
+    pub fields: Vec<String>,
+}
+
+pub struct Merged {
+    pub data: SaveData,
+    pub report: MergeReport,
+}
+
+#[derive(Debug, Default)]
+pub struct MergeReport {
+    pub conflicts: Vec<Conflict>,
+    // (old, new) ids of tasks we added that clashed with tasks they added
+    pub renumbered: Vec<(u32, u32)>,
+}
+
+
+// a side that left the value alone takes the other side's value
+fn pick<T: Serialize + Clone>(base: &T, ours: &T, theirs: &T) -> T {
+    if same(ours, base) { theirs.clone() } else { ours.clone() }
+}
+
+fn as_object(task: &Task) -> Map<String, Value> {
+    match serde_json::to_value(task) {
+        Ok(Value::Object(map)) => map,
+        _ => Map::new(),
+    }
+}
+
+fn timestamp(value: &Value) -> Option<DateTime<chrono::FixedOffset>> {
+    value.as_str().and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+}
+
+/// Merges a task changed on both sides field by field. Fields only one side
+/// changed take that side's value, the others are returned as conflicts
+/// (the merged task keeps our value for them). Bookkeeping fields never
+/// conflict: the activity logs are combined, the latest update time wins
+/// and our completion time is kept.
+pub fn merge_task(base: &Task, ours: &Task, theirs: &Task) -> (Task, Vec<FieldConflict>) {
+    let (base, theirs_map) = (as_object(base), as_object(theirs));
+    let mut merged = as_object(ours);
+    let mut conflicts = ours.conflicts().to_vec();
+    for c in theirs.conflicts() {
+        if !conflicts.iter().any(|o| o.field == c.field) { conflicts.push(c.clone()); }
+    }
+    let mut fields: Vec<String> = merged.keys().chain(theirs_map.keys()).cloned().collect();
+    fields.sort();
+    fields.dedup();
+
+    let mut new_conflicts = Vec::<FieldConflict>::new();
+    for field in fields.into_iter().filter(|f| f != "conflicts") {
+        let b = base.get(&field).cloned().unwrap_or(Value::Null);
+        let o = merged.get(&field).cloned().unwrap_or(Value::Null);
+        let t = theirs_map.get(&field).cloned().unwrap_or(Value::Null);
+        if o == t || t == b { continue; }
+        if o == b {
+            merged.insert(field, t);
+            continue;
+        }
+        match field.as_str() {
+            "activity" => {
+                let mut entries = o.as_array().cloned().unwrap_or_default();
+                for e in t.as_array().cloned().unwrap_or_default() {
+                    if !entries.contains(&e) { entries.push(e); }
+                }
+                entries.sort_by_key(|e| timestamp(&e["at"]));
+                merged.insert(field, Value::Array(entries));
+            },
+            "updated_at" => {
+                if timestamp(&t) > timestamp(&o) { merged.insert(field, t); }
+            },
+            "completed_at" => { },
+            _ => { new_conflicts.push(FieldConflict { field, ours: o, theirs: t }); },
+        };
+    }
+    conflicts.retain(|c| !new_conflicts.iter().any(|n| n.field == c.field));
+    conflicts.extend(new_conflicts.iter().cloned());
+    merged.insert("conflicts".to_string(), serde_json::to_value(&conflicts).unwrap_or(Value::Null));
+
+    match serde_json::from_value::<Task>(Value::Object(merged)) {
+        Ok(task) => (task, new_conflicts),
+        // can't happen with values taken from tasks, but ours is a safe bet
+        Err(_) => (ours.clone(), Vec::new()),
+    }
+}
+
+// the ids a task's parent or dependencies field refers to
+fn targets(value: Option<&Value>) -> Vec<u32> {
+    match value {
+        Some(Value::Array(ids)) => ids.iter().filter_map(|v| v.as_u64()).map(|v| v as u32).collect(),
+        Some(v) => v.as_u64().map(|v| v as u32).into_iter().collect(),
+        None => Vec::new(),
+    }
+}
+
+// the field without the reference to `id`
+fn without(value: &Value, id: u32) -> Value {
+    match value {
+        Value::Array(ids) => Value::Array(ids.iter().filter(|v| v.as_u64() != Some(id as u64)).cloned().collect()),
+        _ => Value::Null,
+    }
+}
+
+/// Each side kept its tree and dependencies free of cycles, but together
+/// they can close one (we move a task under one of theirs while they move
+/// that one under ours). The task in the cycle that took their value gets
+/// ours back and the field is marked as conflicting, a cycle made only of
+/// our values is cut.
+fn break_cycles(merged: &mut [(String, Task)], ours: &[(String, Task)], theirs: &HashMap<u32, &(String, Task)>, conflicts: &mut Vec<Conflict>) {
+    let ours: HashMap<u32, &Task> = ours.iter().map(|e| (e.1.id(), &e.1)).collect();
+    for field in ["parent", "depends_on"] {
+        let value = |task: Option<&Task>| task.and_then(|t| as_object(t).remove(field)).unwrap_or(Value::Null);
+        let edges = |merged: &[(String, Task)]| -> HashMap<u32, Vec<u32>> {
+            merged.iter().map(|e| (e.1.id(), targets(as_object(&e.1).get(field)))).collect()
+        };
+        // tasks already given back our value, so they can't go back and forth
+        let mut settled = HashSet::<u32>::new();
+        while let Some(cycle) = find_cycle(&edges(merged)) {
+            let current = |id: u32| value(merged.iter().find(|e| e.1.id() == id).map(|e| &e.1));
+            let (id, kept) = match cycle.iter().find(|id| !settled.contains(id) && current(**id) != value(ours.get(id).copied())) {
+                Some(&id) => (id, value(ours.get(&id).copied())),
+                None => (cycle[0], without(&current(cycle[0]), cycle[1 % cycle.len()])),
+            };
+            settled.insert(id);
+            let entry = match merged.iter_mut().find(|e| e.1.id() == id) {
+                Some(entry) => entry,
+                None => { break; },
+            };
+            let mut map = as_object(&entry.1);
+            map.insert(field.to_string(), kept.clone());
+            if let Ok(task) = serde_json::from_value::<Task>(Value::Object(map)) { entry.1 = task; }
+            let theirs_task = theirs.get(&id).map(|e| &e.1);
+            entry.1.mark_conflict(FieldConflict { field: field.to_string(), ours: kept, theirs: value(theirs_task) });
+            match conflicts.iter_mut().find(|c| c.id == id) {
+                Some(c) => { if !c.fields.iter().any(|f| f == field) { c.fields.push(field.to_string()); } },
+                None => {
+                    conflicts.push(Conflict {
+                        id,
+                        ours: ours.get(&id).map(|t| (*t).clone()),
+                        theirs: theirs_task.cloned(),
+                        fields: vec![field.to_string()],
+                    });
+                },
+            };
+        }
+    }
+}
+
+// every task along with the name of its project, in file order
+fn flatten(data: &SaveData) -> Vec<(String, Task)> {
+    data.projects
+        .iter()
+        .flat_map(|p| p.tasks.iter().map(|t| (p.name.clone(), t.clone())))
+        .collect()
+}
+
+/// Three-way merge of two versions of a task file that both started out
+/// as `base`, task by task. Tasks changed on only one side are taken as
+/// they are, tasks changed on both sides are merged field by field (see
+/// `merge_task`) and the fields that conflict are marked on them.
+pub fn merge(base: &SaveData, ours: &SaveData, theirs: &SaveData) -> Merged {
+    let base_tasks: HashMap<u32, (String, Task)> = flatten(base)
+        .into_iter()
+        .map(|e| (e.1.id(), e))
+        .collect();
+    let theirs_list = flatten(theirs);
+    let theirs_tasks: HashMap<u32, &(String, Task)> = theirs_list
+        .iter()
+        .map(|e| (e.1.id(), e))
+        .collect();
+    let mut ours_list = flatten(ours);
+
+    // both sides handing out the same new id to different tasks is the
+    // common case of two instances adding tasks, ours move to fresh ids
+    let mut next_id = ours.next_id
+        .max(theirs.next_id)
+        .max(ours_list.iter().chain(theirs_list.iter()).map(|e| e.1.id() + 1).max().unwrap_or(0));
+    let mut renumbered = HashMap::<u32, u32>::new();
+    for entry in ours_list.iter() {
+        let id = entry.1.id();
+        if base_tasks.contains_key(&id) { continue; }
+        if let Some(other) = theirs_tasks.get(&id) {
+            if !same(entry, *other) {
+                renumbered.insert(id, next_id);
+                next_id += 1;
+            }
+        }
+    }
+    if !renumbered.is_empty() {
+        for (_, t) in ours_list.iter_mut() {
+            t.renumber(&renumbered);
+        }
+    }
+
+    let mut merged = Vec::<(String, Task)>::new();
+    let mut conflicts = Vec::<Conflict>::new();
+    for entry in ours_list.iter() {
+        let id = entry.1.id();
+        match (base_tasks.get(&id), theirs_tasks.get(&id)) {
+            (_, Some(other)) if same(entry, *other) => { merged.push(entry.clone()); },
+            (Some(original), Some(other)) => {
+                if same(entry, original) {
+                    merged.push((*other).clone());
+                } else if same(*other, original) {
+                    merged.push(entry.clone());
+                } else {
+                    let (task, fields) = merge_task(&original.1, &entry.1, &other.1);
+                    if !fields.is_empty() {
+                        conflicts.push(Conflict {
+                            id,
+                            ours: Some(entry.1.clone()),
+                            theirs: Some(other.1.clone()),
+                            fields: fields.into_iter().map(|f| f.field).collect(),
+                        });
+                    }
+                    merged.push((entry.0.clone(), task));
+                }
+            },
+            // removed on their side
+            (Some(original), None) => {
+                if !same(entry, original) {
+                    conflicts.push(Conflict { id, ours: Some(entry.1.clone()), theirs: None, fields: vec![format!("task")] });
+                    let mut task = entry.1.clone();
+                    task.mark_conflict(FieldConflict::removal(false));
+                    merged.push((entry.0.clone(), task));
+                }
+            },
+            // added on our side
+            (None, _) => { merged.push(entry.clone()); },
+        };
+    }
+    let ours_ids: Vec<u32> = ours_list.iter().map(|e| e.1.id()).collect();
+    for entry in theirs_list.iter().filter(|e| !ours_ids.contains(&e.1.id())) {
+        let id = entry.1.id();
+        match base_tasks.get(&id) {
+            // removed on our side
+            Some(original) => {
+                if !same(entry, original) {
+                    conflicts.push(Conflict { id, ours: None, theirs: Some(entry.1.clone()), fields: vec![format!("task")] });
+                    let mut task = entry.1.clone();
+                    task.mark_conflict(FieldConflict::removal(true));
+                    merged.push((entry.0.clone(), task));
+                }
+            },
+            // added on their side
+            None => { merged.push(entry.clone()); },
+        };
+    }
+
+    break_cycles(&mut merged, &ours_list, &theirs_tasks, &mut conflicts);
+
+    // projects keep our order, the ones only they have go last
+    let mut projects: Vec<Project> = ours.projects
+        .iter()
+        .chain(theirs.projects.iter())
+        .map(|p| Project { name: p.name.clone(), tasks: Vec::new() })
+        .collect();
+    let mut seen = Vec::<String>::new();
+    projects.retain(|p| {
+        if seen.contains(&p.name) { return false; }
+        seen.push(p.name.clone());
+        true
+    });
+    for (project, task) in merged {
+        match projects.iter_mut().find(|p| p.name == project) {
+            Some(p) => { p.tasks.push(task); },
+            None => { projects.push(Project { name: project, tasks: vec![task] }); },
+        };
+    }
+
+    let mut renumbered: Vec<(u32, u32)> = renumbered.into_iter().collect();
+    renumbered.sort();
+    Merged {
+        data: SaveData {
+            next_id,
+            workflow: pick(&base.workflow, &ours.workflow, &theirs.workflow),
+            priority_scale: pick(&base.priority_scale, &ours.priority_scale, &theirs.priority_scale),
+            auto_archive_days: pick(&base.auto_archive_days, &ours.auto_archive_days, &theirs.auto_archive_days),
+            autosave: pick(&base.autosave, &ours.autosave, &theirs.autosave),
+            active_project: ours.active_project.clone(),
+            projects,
+        },
+        report: MergeReport { conflicts, renumbered },
+    }
+}
+
+/// Merges three task files the way git merge drivers do: the result is
+/// written over `ours`. To have git use it for the task file:
+///
+///     echo 'taskman.json merge=taskman' >> .gitattributes
+///     git config merge.taskman.driver 'taskman merge %O %A %B'
+pub fn merge_files(base: &Path, ours: &Path, theirs: &Path) -> Result<MergeReport, String> {
+    let read = |path: &Path| -> Result<SaveData, String> {
+        let content = std::fs::read(path)
+            .map_err(|e| format!("could not read '{}': {}...", path.display(), e))?;
+        if crypto::is_encrypted(&content) {
+            return Err(format!("'{}' is encrypted, encrypted task files can't be merged...", path.display()));
+        }
+        schema::load(&String::from_utf8_lossy(&content))
+            .map(|(data, _)| data)
+            .map_err(|e| format!("couldn't load file '{}': {}", path.display(), e))
+    };
+    let merged = merge(&read(base)?, &read(ours)?, &read(theirs)?);
+    let content = serde_json::to_string(&merged.data.borrowed())
+        .map_err(|e| format!("could not write the merged tasks: {}...", e))?;
+    backup::write_atomic(ours, content.as_bytes())
+        .map_err(|e| format!("could not write '{}': {}...", ours.display(), e))?;
+    Ok(merged.report)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // a task file with the (id, title, status) tasks in the default project
+    fn data(tasks: &[(u32, &str, &str)]) -> SaveData {
+        let tasks: Vec<Value> = tasks
+            .iter()
+            .map(|(id, title, status)| json!({
+                "id": id, "hash": format!("{:04x}", 0xa000 + id), "title": title,
+                "description": "", "priority": 0, "status": status,
+            }))
+            .collect();
+        let content = json!({ "version": 2, "next_id": 0, "projects": [{ "name": "default", "tasks": tasks }] });
+        schema::load(&content.to_string()).unwrap().0
+    }
+
+    fn task(data: &SaveData, id: u32) -> Option<&Task> {
+        data.projects.iter().flat_map(|p| p.tasks.iter()).find(|t| t.id() == id)
+    }
+
+    // sets a task's field the way editing it would
+    fn set(data: &mut SaveData, id: u32, field: &str, value: Value) {
+        let t = data.projects.iter_mut().flat_map(|p| p.tasks.iter_mut()).find(|t| t.id() == id).unwrap();
+        let mut map = as_object(t);
+        map.insert(field.to_owned(), value);
+        *t = serde_json::from_value(Value::Object(map)).unwrap();
+    }
+
+    #[test]
+    fn changes_to_different_tasks_merge_cleanly() {
+        let base = data(&[(0, "a", "ToDo"), (1, "b", "ToDo")]);
+        let ours = data(&[(0, "a2", "ToDo"), (1, "b", "ToDo")]);
+        let theirs = data(&[(0, "a", "ToDo"), (1, "b", "Done")]);
+        let merged = merge(&base, &ours, &theirs);
+        assert!(merged.report.conflicts.is_empty());
+        assert_eq!(task(&merged.data, 0).unwrap().title(), "a2");
+        assert_eq!(task(&merged.data, 1).unwrap().status().name(), "Done");
+    }
+
+    #[test]
+    fn different_fields_of_a_task_merge_cleanly() {
+        let base = data(&[(0, "a", "ToDo")]);
+        let ours = data(&[(0, "a2", "ToDo")]);
+        let theirs = data(&[(0, "a", "Done")]);
+        let (task, conflicts) = merge_task(&base.projects[0].tasks[0], &ours.projects[0].tasks[0], &theirs.projects[0].tasks[0]);
+        assert!(conflicts.is_empty());
+        assert_eq!((task.title(), task.status().name()), ("a2", "Done"));
+    }
+
+    #[test]
+    fn the_same_field_changed_on_both_sides_conflicts() {
+        let base = data(&[(0, "a", "ToDo")]);
+        let ours = data(&[(0, "ours", "Doing")]);
+        let theirs = data(&[(0, "theirs", "Doing")]);
+        let merged = merge(&base, &ours, &theirs);
+        assert_eq!(merged.report.conflicts.len(), 1);
+        assert_eq!(merged.report.conflicts[0].fields, vec!["title"]);
+
+        // ours is kept until the conflict is resolved
+        let task = task(&merged.data, 0).unwrap();
+        assert_eq!((task.title(), task.status().name()), ("ours", "Doing"));
+        assert_eq!(task.conflicts(), &[FieldConflict { field: "title".to_owned(), ours: json!("ours"), theirs: json!("theirs") }]);
+    }
+
+    #[test]
+    fn tasks_added_on_both_sides_with_the_same_id_are_renumbered() {
+        let base = data(&[(0, "a", "ToDo")]);
+        let ours = data(&[(0, "a", "ToDo"), (1, "ours", "ToDo")]);
+        let theirs = data(&[(0, "a", "ToDo"), (1, "theirs", "ToDo")]);
+        let merged = merge(&base, &ours, &theirs);
+        assert!(merged.report.conflicts.is_empty());
+        assert_eq!(merged.report.renumbered, vec![(1, 2)]);
+        assert_eq!(task(&merged.data, 1).unwrap().title(), "theirs");
+        assert_eq!(task(&merged.data, 2).unwrap().title(), "ours");
+        assert_eq!(merged.data.next_id, 3);
+    }
+
+    #[test]
+    fn removals_only_conflict_with_changes() {
+        let base = data(&[(0, "a", "ToDo"), (1, "b", "ToDo")]);
+        let ours = data(&[(1, "b", "ToDo")]);
+        let theirs = data(&[(0, "a2", "ToDo")]);
+        let merged = merge(&base, &ours, &theirs);
+
+        // we removed 0 and they changed it: it's kept, marked for resolution
+        assert_eq!(merged.report.conflicts.len(), 1);
+        assert_eq!(merged.report.conflicts[0].id, 0);
+        assert_eq!(merged.report.conflicts[0].fields, vec!["task"]);
+        assert_eq!(task(&merged.data, 0).unwrap().conflicts(), &[FieldConflict::removal(true)]);
+        // they removed 1, which we left alone
+        assert!(task(&merged.data, 1).is_none());
+    }
+
+    #[test]
+    fn changes_that_close_a_cycle_together_conflict() {
+        let base = data(&[(0, "a", "ToDo"), (1, "b", "ToDo")]);
+        let (mut ours, mut theirs) = (base.clone(), base.clone());
+        set(&mut ours, 0, "parent", json!(1));
+        set(&mut ours, 0, "depends_on", json!([1]));
+        set(&mut theirs, 1, "parent", json!(0));
+        set(&mut theirs, 1, "depends_on", json!([0]));
+        let merged = merge(&base, &ours, &theirs);
+
+        // ours is kept, their side of the cycle is marked for resolution
+        let (a, b) = (task(&merged.data, 0).unwrap(), task(&merged.data, 1).unwrap());
+        assert_eq!((a.parent(), a.depends_on().len()), (Some(1), 1));
+        assert_eq!((b.parent(), b.depends_on().len()), (None, 0));
+        assert_eq!(merged.report.conflicts.len(), 1);
+        assert_eq!(merged.report.conflicts[0].id, 1);
+        assert_eq!(merged.report.conflicts[0].fields, vec!["parent", "depends_on"]);
+        assert_eq!(b.conflicts()[0], FieldConflict { field: "parent".to_owned(), ours: json!(null), theirs: json!(0) });
+    }
+
+    #[test]
+    fn encrypted_files_are_refused() {
+        let dir = crate::testdir::TestDir::new();
+        let plain = serde_json::to_string(&data(&[(0, "a", "ToDo")]).borrowed()).unwrap();
+        let encrypted = crypto::Cipher::new("secret").unwrap().encrypt(plain.as_bytes()).unwrap();
+        let (base, ours, theirs) = (dir.join("base"), dir.join("ours"), dir.join("theirs"));
+        std::fs::write(&base, &plain).unwrap();
+        std::fs::write(&ours, &encrypted).unwrap();
+        std::fs::write(&theirs, &plain).unwrap();
+
+        let err = merge_files(&base, &ours, &theirs).err().unwrap();
+        assert!(err.contains("is encrypted"), "{}", err);
+        // ours is left untouched
+        assert_eq!(std::fs::read(&ours).unwrap(), encrypted);
+    }
+}
@@ -21,6 +21,10 @@ where T: std::fmt::Debug + Clone
         self.elements.push_back(e);
     }
 
+    pub fn last(&self) -> Option<&T> {
+        self.elements.back()
+    }
+
     pub fn clone_elements(&self) -> Vec<T> {
         let mut res = Vec::<T>::new();
         for e in self.elements.iter() {
@@ -0,0 +1,218 @@
+use std::{
+    fmt::Display,
+    str::FromStr,
+};
+
+use chrono::{Datelike, Days, Months, NaiveDate, Weekday};
+use serde::{Serialize, Deserialize};
+
+// the longest gap `AfterCompletion` accepts, ten years
+pub const MAX_DAYS: u32 = 3650;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(try_from = "RawRecurrence")]
+pub enum Recurrence {
+    Daily,
+    Weekly(Vec<Weekday>),
+    // on the given day of every month (clamped to the month's length)
+    Monthly(u32),
+    // the given number of days after the previous occurrence was completed
+    AfterCompletion(u32),
+}
+
+// a recurrence as it's read from a file, before its values are checked
+#[derive(Deserialize)]
+enum RawRecurrence {
+    Daily,
+    Weekly(Vec<Weekday>),
+    Monthly(u32),
+    AfterCompletion(u32),
+}
+
+impl TryFrom<RawRecurrence> for Recurrence {
+    type Error = String;
+
+    fn try_from(raw: RawRecurrence) -> Result<Self, Self::Error> {
+        match raw {
+            RawRecurrence::Daily => Ok(Self::Daily),
+            RawRecurrence::Weekly(days) => Ok(Self::Weekly(days)),
+            RawRecurrence::Monthly(day) if (1..=31).contains(&day) => Ok(Self::Monthly(day)),
+            RawRecurrence::Monthly(day) => Err(format!("day of the month {} isn't between 1 and 31", day)),
+            RawRecurrence::AfterCompletion(days) if (1..=MAX_DAYS).contains(&days) => Ok(Self::AfterCompletion(days)),
+            RawRecurrence::AfterCompletion(days) => Err(format!("{} days isn't between 1 and {}", days, MAX_DAYS)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseRecurrenceError;
+
+/// Accepted forms: daily, weekly:mon,thu, monthly:15, after:3d
+impl FromStr for Recurrence {
+    type Err = ParseRecurrenceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        let (kind, arg) = match s.split_once(':') {
+            Some((kind, arg)) => (kind, Some(arg)),
+            None => (s.as_str(), None),
+        };
+        match (kind, arg) {
+            ("daily", None) => Ok(Self::Daily),
+            ("weekly", Some(days)) => {
+                let mut weekdays = days
+                    .split(',')
+                    .map(|d| d.parse::<Weekday>().ok().ok_or(ParseRecurrenceError))
+                    .collect::<Result<Vec<Weekday>, ParseRecurrenceError>>()?;
+                weekdays.sort_by_key(|d| d.num_days_from_monday());
+                weekdays.dedup();
+                Ok(Self::Weekly(weekdays))
+            },
+            ("monthly", Some(day)) => {
+                let day = day.parse::<u32>().ok().ok_or(ParseRecurrenceError)?;
+                if !(1..=31).contains(&day) { return Err(ParseRecurrenceError); }
+                Ok(Self::Monthly(day))
+            },
+            ("after", Some(days)) => {
+                let days = days
+                    .strip_suffix('d')
+                    .ok_or(ParseRecurrenceError)?
+                    .parse::<u32>()
+                    .ok().ok_or(ParseRecurrenceError)?;
+                if !(1..=MAX_DAYS).contains(&days) { return Err(ParseRecurrenceError); }
+                Ok(Self::AfterCompletion(days))
+            },
+            _ => Err(ParseRecurrenceError),
+        }
+    }
+}
+
+impl Display for Recurrence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Daily => write!(f, "daily"),
+            Self::Weekly(days) => write!(f, "weekly:{}", days
+                .iter()
+                .map(|d| d.to_string().to_lowercase())
+                .collect::<Vec<String>>()
+                .join(",")),
+            Self::Monthly(day) => write!(f, "monthly:{}", day),
+            Self::AfterCompletion(days) => write!(f, "after:{}d", days),
+        }
+    }
+}
+
+impl Recurrence {
+    /// The due date of the occurrence following one that was due on `due`
+    /// and got completed on `completed`. Occurrences missed by completing
+    /// late are skipped, so the result is always after `completed`. None
+    /// past the last date chrono can represent.
+    pub fn next_due(&self, due: Option<NaiveDate>, completed: NaiveDate) -> Option<NaiveDate> {
+        if let Self::AfterCompletion(days) = self {
+            return completed.checked_add_days(Days::new(*days as u64));
+        }
+        let mut next = self.after(due.unwrap_or(completed))?;
+        while next <= completed {
+            next = self.after(next)?;
+        }
+        Some(next)
+    }
+
+    // the first date strictly after `date` matching the rule
+    fn after(&self, date: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            Self::Daily => date.succ_opt(),
+            Self::Weekly(days) => {
+                if days.is_empty() { return date.checked_add_days(Days::new(7)); }
+                let mut next = date.succ_opt()?;
+                while !days.contains(&next.weekday()) {
+                    next = next.succ_opt()?;
+                }
+                Some(next)
+            },
+            Self::Monthly(day) => {
+                let this_month = date.with_day(1).unwrap_or(date);
+                let candidate = clamp_day(this_month, *day);
+                if candidate > date {
+                    Some(candidate)
+                } else {
+                    Some(clamp_day(this_month.checked_add_months(Months::new(1))?, *day))
+                }
+            },
+            Self::AfterCompletion(days) => date.checked_add_days(Days::new(*days as u64)),
+        }
+    }
+}
+
+// the given day of the month `first` is in, or the month's last day if it's too short
+fn clamp_day(first: NaiveDate, day: u32) -> NaiveDate {
+    (1..=day.min(31))
+        .rev()
+        .find_map(|d| first.with_day(d))
+        .unwrap_or(first)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn rules_parse_and_print_the_same() {
+        for rule in ["daily", "weekly:mon,thu", "monthly:15", "after:3d"] {
+            assert_eq!(rule.parse::<Recurrence>().unwrap().to_string(), rule);
+        }
+        assert_eq!("weekly:thu,mon,thu".parse::<Recurrence>().unwrap(), Recurrence::Weekly(vec![Weekday::Mon, Weekday::Thu]));
+        for bad in ["hourly", "weekly:someday", "monthly:32", "monthly:0", "after:0d", "after:3", "after:99999999d"] {
+            assert!(bad.parse::<Recurrence>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn next_due_follows_the_rule() {
+        let completed = date("2024-06-03");
+        assert_eq!(Recurrence::Daily.next_due(Some(completed), completed), Some(date("2024-06-04")));
+        // 2024-06-03 is a Monday
+        let weekly = Recurrence::Weekly(vec![Weekday::Mon, Weekday::Thu]);
+        assert_eq!(weekly.next_due(Some(completed), completed), Some(date("2024-06-06")));
+        assert_eq!(Recurrence::Monthly(15).next_due(Some(completed), completed), Some(date("2024-06-15")));
+        // without a due date it counts from the completion
+        assert_eq!(Recurrence::Daily.next_due(None, completed), Some(date("2024-06-04")));
+    }
+
+    #[test]
+    fn monthly_days_are_clamped_to_short_months() {
+        let jan = date("2024-01-31");
+        assert_eq!(Recurrence::Monthly(31).next_due(Some(jan), jan), Some(date("2024-02-29")));
+        let feb = date("2024-02-29");
+        assert_eq!(Recurrence::Monthly(31).next_due(Some(feb), feb), Some(date("2024-03-31")));
+    }
+
+    #[test]
+    fn completing_late_skips_missed_occurrences() {
+        let due = date("2024-06-01");
+        let completed = date("2024-06-05");
+        assert_eq!(Recurrence::Daily.next_due(Some(due), completed), Some(date("2024-06-06")));
+        assert_eq!(Recurrence::Monthly(1).next_due(Some(due), completed), Some(date("2024-07-01")));
+        // counted from the completion, not the due date
+        assert_eq!(Recurrence::AfterCompletion(3).next_due(Some(due), completed), Some(date("2024-06-08")));
+    }
+
+    #[test]
+    fn out_of_range_values_in_a_file_are_rejected() {
+        assert_eq!(serde_json::from_str::<Recurrence>(r#"{"Monthly": 31}"#).unwrap(), Recurrence::Monthly(31));
+        assert!(serde_json::from_str::<Recurrence>(r#"{"Monthly": 0}"#).is_err());
+        assert!(serde_json::from_str::<Recurrence>(r#"{"AfterCompletion": 4294967295}"#).is_err());
+    }
+
+    #[test]
+    fn occurrences_past_the_last_date_have_no_due_date() {
+        assert_eq!(Recurrence::AfterCompletion(MAX_DAYS).next_due(None, NaiveDate::MAX), None);
+        assert_eq!(Recurrence::Monthly(1).next_due(None, NaiveDate::MAX), None);
+    }
+}
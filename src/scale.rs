@@ -0,0 +1,85 @@
+use serde::{Serialize, Deserialize};
+
+use super::taskmanager::Priority;
+
+pub const MAX_LEVELS: usize = 9;
+
+/// The priority levels a task file uses, from the lowest to the highest.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct PriorityScale {
+    names: Vec<String>,
+}
+
+impl Default for PriorityScale {
+    fn default() -> Self {
+        PriorityScale { names: vec!["Low".to_owned(), "Medium".to_owned(), "High".to_owned()] }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ScaleError {
+    Size,
+    DuplicateLevel(String),
+}
+
+impl PriorityScale {
+    pub fn new(names: Vec<String>) -> Result<PriorityScale, ScaleError> {
+        let scale = PriorityScale { names };
+        scale.validate()?;
+        Ok(scale)
+    }
+
+    pub fn validate(&self) -> Result<(), ScaleError> {
+        if self.names.is_empty() || self.names.len() > MAX_LEVELS {
+            return Err(ScaleError::Size);
+        }
+        for (i, n) in self.names.iter().enumerate() {
+            if self.names[..i].iter().any(|o| o.eq_ignore_ascii_case(n)) {
+                return Err(ScaleError::DuplicateLevel(n.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// A scale of plain numbers, 1 (lowest) to `levels`.
+    pub fn numeric(levels: usize) -> Result<PriorityScale, ScaleError> {
+        PriorityScale::new((1..=levels).map(|l| l.to_string()).collect())
+    }
+
+    pub fn levels(&self) -> usize { self.names.len() }
+
+    pub fn highest(&self) -> Priority {
+        Priority::new((self.names.len() - 1) as u8)
+    }
+
+    /// Finds a priority by level name (ignoring case) or by its number on
+    /// the scale, 1 being the lowest.
+    pub fn resolve(&self, s: &str) -> Option<Priority> {
+        if let Some(level) = self.names.iter().position(|n| n.eq_ignore_ascii_case(s)) {
+            return Some(Priority::new(level as u8));
+        }
+        match s.parse::<usize>() {
+            Ok(n) if (1..=self.names.len()).contains(&n) => Some(Priority::new((n - 1) as u8)),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self, priority: Priority) -> String {
+        match self.names.get(priority.level() as usize) {
+            Some(name) => name.clone(),
+            None => priority.to_string(),
+        }
+    }
+
+    /// Maps a priority from another scale onto this one, keeping its
+    /// relative position (e.g. Medium on Low/Medium/High becomes 5 on 1-9).
+    pub fn rescale(&self, priority: Priority, from: &PriorityScale) -> Priority {
+        if from.levels() <= 1 || self.levels() <= 1 {
+            return Priority::new(0);
+        }
+        let level = priority.level().min((from.levels() - 1) as u8) as usize;
+        let scaled = (level * (self.levels() - 1) + (from.levels() - 1) / 2) / (from.levels() - 1);
+        Priority::new(scaled as u8)
+    }
+}
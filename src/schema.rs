@@ -0,0 +1,200 @@
+use std::fmt::Display;
+
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+
+/// Version written to the save file's `version` field. Bump it together
+/// with a new entry in `MIGRATIONS` whenever the layout changes in a way
+/// serde defaults can't absorb.
+pub const CURRENT_VERSION: u64 = 2;
+
+// MIGRATIONS[n] upgrades a version n file to version n + 1
+const MIGRATIONS: [fn(Value) -> Value; CURRENT_VERSION as usize] = [
+    migrate_v0,
+    migrate_v1,
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SchemaError {
+    // not valid JSON at all
+    Syntax { line: usize, column: usize, msg: String },
+    // valid JSON that doesn't fit the schema, `line` is 0 when the error was
+    // found after migrating (the location in the file is unknown then)
+    Data { path: String, line: usize, column: usize, msg: String },
+    UnknownVersion(Value),
+    TooNew(u64),
+}
+
+impl Display for SchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchemaError::Syntax { line, column, msg } =>
+                write!(f, "invalid JSON at line {}, column {}: {}...", line, column, msg),
+            SchemaError::Data { path, line: 0, msg, .. } =>
+                write!(f, "invalid value at '{}': {}...", path, msg),
+            SchemaError::Data { path, line, column, msg } =>
+                write!(f, "invalid value at '{}' (line {}, column {}): {}...", path, line, column, msg),
+            SchemaError::UnknownVersion(v) => write!(f, "unknown file version '{}'...", v),
+            SchemaError::TooNew(v) =>
+                write!(f, "file version {} is newer than the supported {}, update taskman...", v, CURRENT_VERSION),
+        }
+    }
+}
+
+// serde_json's message without the location it appends, which is reported
+// separately
+fn message(e: &serde_json::Error) -> String {
+    let msg = e.to_string();
+    let location = format!(" at line {} column {}", e.line(), e.column());
+    msg.strip_suffix(&location).unwrap_or(&msg).to_owned()
+}
+
+/// Version of a save file's content:
+/// * 0: a bare array of tasks
+/// * 1: an object without a `version` field
+/// * 2 and up: the `version` field
+fn version_of(value: &Value) -> Result<u64, SchemaError> {
+    match value {
+        Value::Array(_) => Ok(0),
+        Value::Object(o) => match o.get("version") {
+            None => Ok(1),
+            Some(v) => v.as_u64().ok_or(SchemaError::UnknownVersion(v.clone())),
+        },
+        v => Err(SchemaError::UnknownVersion(v.clone())),
+    }
+}
+
+/// Parses a save file of any known version, upgrading it to the current
+/// one. Returns the data along with the version the file was written in.
+/// An empty file is an empty task list.
+pub fn load<T: DeserializeOwned>(content: &str) -> Result<(T, u64), SchemaError> {
+    let content = if content.trim().is_empty() { "[]" } else { content };
+    let mut value: Value = serde_json::from_str(content)
+        .map_err(|e| SchemaError::Syntax { line: e.line(), column: e.column(), msg: message(&e) })?;
+
+    let version = version_of(&value)?;
+    if version > CURRENT_VERSION {
+        return Err(SchemaError::TooNew(version));
+    }
+
+    // deserializing the text itself keeps line and column in the errors
+    if version == CURRENT_VERSION {
+        let mut de = serde_json::Deserializer::from_str(content);
+        return serde_path_to_error::deserialize(&mut de)
+            .map(|data| (data, version))
+            .map_err(|e| SchemaError::Data {
+                path: e.path().to_string(),
+                line: e.inner().line(),
+                column: e.inner().column(),
+                msg: message(e.inner()),
+            });
+    }
+
+    for migrate in MIGRATIONS[version as usize..].iter() {
+        value = migrate(value);
+    }
+    serde_path_to_error::deserialize(value)
+        .map(|data| (data, version))
+        .map_err(|e| SchemaError::Data {
+            path: e.path().to_string(),
+            line: 0,
+            column: 0,
+            msg: message(e.inner()),
+        })
+}
+
+// a bare array of tasks becomes an object holding the tasks, the id counter
+// is recomputed from the tasks on load
+fn migrate_v0(value: Value) -> Value {
+    json!({ "next_id": 0, "tasks": value })
+}
+
+// the single task list of files from before projects existed moves into
+// the default project, and the version starts being recorded
+fn migrate_v1(value: Value) -> Value {
+    let mut object = match value {
+        Value::Object(o) => o,
+        _ => Map::new(),
+    };
+    let tasks = match object.remove("tasks") {
+        Some(Value::Array(tasks)) => tasks,
+        _ => Vec::new(),
+    };
+    if !tasks.is_empty() {
+        let projects = object
+            .entry("projects")
+            .or_insert(Value::Array(Vec::new()));
+        if let Value::Array(projects) = projects {
+            let default = projects
+                .iter_mut()
+                .find(|p| p.get("name").and_then(|n| n.as_str()) == Some("default"));
+            match default.and_then(|p| p.get_mut("tasks")).and_then(|t| t.as_array_mut()) {
+                Some(existing) => { existing.splice(0..0, tasks); },
+                None => { projects.insert(0, json!({ "name": "default", "tasks": tasks })); },
+            };
+        }
+    }
+    object.insert("version".to_owned(), json!(2));
+    Value::Object(object)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::SaveData;
+    use crate::scale::PriorityScale;
+
+    fn titles(data: &SaveData, project: &str) -> Vec<String> {
+        data.projects
+            .iter()
+            .find(|p| p.name == project)
+            .map(|p| p.tasks.iter().map(|t| t.title().to_owned()).collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn bare_task_arrays_are_version_0() {
+        let (data, version) = load::<SaveData>(
+            r#"[{"id": 0, "title": "a", "description": "", "priority": "High", "status": "ToDo"}]"#).unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(titles(&data, "default"), vec!["a"]);
+        // names from before the priority scale existed still resolve
+        assert_eq!(Some(data.projects[0].tasks[0].priority()), PriorityScale::default().resolve("High"));
+    }
+
+    #[test]
+    fn version_1_tasks_move_into_the_default_project() {
+        let (data, version) = load::<SaveData>(r#"{"next_id": 2, "tasks": [
+            {"id": 1, "title": "b", "description": "", "priority": 0, "status": "ToDo"}
+        ], "projects": [{"name": "default", "tasks": [
+            {"id": 0, "title": "a", "description": "", "priority": 0, "status": "ToDo"}
+        ]}]}"#).unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(data.next_id, 2);
+        assert_eq!(data.projects.len(), 1);
+        assert_eq!(titles(&data, "default"), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn current_and_empty_files_load_as_they_are() {
+        let (data, version) = load::<SaveData>(r#"{"version": 2, "next_id": 7, "projects": [{"name": "work", "tasks": []}]}"#).unwrap();
+        assert_eq!((version, data.next_id), (2, 7));
+        assert_eq!(data.projects[0].name, "work");
+
+        let (data, _) = load::<SaveData>("  \n").unwrap();
+        assert!(data.projects.is_empty());
+    }
+
+    #[test]
+    fn errors_say_what_and_where() {
+        assert_eq!(load::<SaveData>(r#"{"version": 3}"#).err(), Some(SchemaError::TooNew(3)));
+        assert!(matches!(load::<SaveData>(r#"{"version": "x"}"#), Err(SchemaError::UnknownVersion(_))));
+        assert!(matches!(load::<SaveData>("{\n\"next_id\": }"), Err(SchemaError::Syntax { line: 2, .. })));
+        match load::<SaveData>(r#"{"version": 2, "next_id": "many"}"#) {
+            Err(SchemaError::Data { path, line: 1, .. }) => { assert_eq!(path, "next_id"); },
+            other => { panic!("unexpected result: {:?}", other.map(|(_, v)| v)); },
+        };
+    }
+}
@@ -0,0 +1,343 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::taskmanager::Task;
+use super::storage::{Change, Project, SaveData, SaveDataRef, Storage};
+use super::lock::FileLock;
+
+// tasks are stored whole as JSON in `data`, the other columns are copies of
+// the fields worth querying on (indexed for filtering by status or priority);
+// a task's position only orders it within its project
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS meta (
+    key   TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS projects (
+    name     TEXT PRIMARY KEY,
+    position INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS tasks (
+    id       INTEGER PRIMARY KEY,
+    project  TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    status   TEXT NOT NULL,
+    priority INTEGER NOT NULL,
+    data     TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS tasks_status ON tasks (project, status);
+CREATE INDEX IF NOT EXISTS tasks_priority ON tasks (project, priority);
+"#;
+
+// a task's row as it is in the database
+struct Row {
+    project: String,
+    position: i64,
+    data: String,
+}
+
+/// Keeps the task file in a SQLite database, one row per task. Changes to
+/// single tasks are written as they happen, each in its own transaction,
+/// and saving only writes the tasks that differ from their rows.
+pub struct SqliteStorage {
+    path: PathBuf,
+    conn: Connection,
+    // changes whenever another connection commits to the database
+    data_version: Option<i64>,
+    // the task rows as last read or written
+    rows: HashMap<u32, Row>,
+    _lock: FileLock,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path) -> Result<SqliteStorage, String> {
+        let lock = FileLock::acquire(path)?;
+        let conn = Connection::open(path)
+            .map_err(|e| format!("could not open database '{}': {}...", path.display(), e))?;
+        conn.execute_batch(SCHEMA)
+            .map_err(|e| format!("could not set up database '{}': {}...", path.display(), e))?;
+        Ok(SqliteStorage { path: path.to_owned(), conn, data_version: None, rows: HashMap::new(), _lock: lock })
+    }
+
+    fn data_version(&self) -> Option<i64> {
+        self.conn.query_row("PRAGMA data_version", [], |r| r.get(0)).ok()
+    }
+
+    fn meta<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, Box<dyn Error>> {
+        let value: Option<String> = self.conn
+            .query_row("SELECT value FROM meta WHERE key = ?1", [key], |r| r.get(0))
+            .optional()?;
+        Ok(match value {
+            Some(v) => Some(serde_json::from_str(&v)?),
+            None => None,
+        })
+    }
+
+    // reads everything, and remembers the task rows as they are and the
+    // version of the database they were read from
+    fn read(&mut self) -> Result<SaveData, Box<dyn Error>> {
+        let mut data = SaveData {
+            next_id: self.meta("next_id")?.unwrap_or(0),
+            workflow: self.meta("workflow")?.unwrap_or_default(),
+            priority_scale: self.meta("priority_scale")?.unwrap_or_default(),
+            auto_archive_days: self.meta("auto_archive_days")?.unwrap_or_default(),
+            autosave: self.meta("autosave")?.unwrap_or_default(),
+            active_project: self.meta("active_project")?,
+            projects: Vec::new(),
+        };
+
+        let mut stmt = self.conn.prepare("SELECT name FROM projects ORDER BY position")?;
+        for name in stmt.query_map([], |r| r.get::<_, String>(0))? {
+            data.projects.push(Project { name: name?, tasks: Vec::new() });
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT t.id, t.project, t.position, t.data FROM tasks t LEFT JOIN projects p ON p.name = t.project
+             ORDER BY p.position IS NULL, p.position, t.position")?;
+        let rows = stmt.query_map([], |r| Ok((r.get::<_, u32>(0)?, Row {
+            project: r.get(1)?,
+            position: r.get(2)?,
+            data: r.get(3)?,
+        })))?;
+        let mut read = HashMap::new();
+        for row in rows {
+            let (id, row) = row?;
+            let task: Task = serde_json::from_str(&row.data)?;
+            match data.projects.iter_mut().find(|p| p.name == row.project) {
+                Some(p) => { p.tasks.push(task); },
+                None => { data.projects.push(Project { name: row.project.clone(), tasks: vec![task] }); },
+            };
+            read.insert(id, row);
+        }
+        drop(stmt);
+        self.rows = read;
+        self.data_version = self.data_version();
+        Ok(data)
+    }
+}
+
+fn put_meta<T: Serialize + ?Sized>(conn: &Connection, key: &str, value: &T) -> Result<(), Box<dyn Error>> {
+    conn.execute(
+        "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+        params![key, serde_json::to_string(value)?])?;
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn load(&mut self) -> Result<(SaveData, Vec<String>), String> {
+        let data = self.read()
+            .map_err(|e| format!("couldn't load database '{}': {}...", self.path.display(), e))?;
+        Ok((data, Vec::new()))
+    }
+
+    fn save(&mut self, data: &SaveDataRef) -> Result<(), Box<dyn Error>> {
+        // overwriting changes made elsewhere has to know what's there now
+        if self.changed_externally() {
+            self.read()?;
+        }
+        let tx = self.conn.transaction()?;
+        put_meta(&tx, "next_id", &data.next_id)?;
+        put_meta(&tx, "workflow", data.workflow)?;
+        put_meta(&tx, "priority_scale", data.priority_scale)?;
+        put_meta(&tx, "auto_archive_days", &data.auto_archive_days)?;
+        put_meta(&tx, "autosave", &data.autosave)?;
+        put_meta(&tx, "active_project", data.active_project)?;
+
+        tx.execute("DELETE FROM projects", [])?;
+        let mut written = Vec::<(u32, Row)>::new();
+        let mut kept = HashSet::<u32>::new();
+        for (i, p) in data.projects.iter().enumerate() {
+            tx.execute("INSERT INTO projects (name, position) VALUES (?1, ?2)", params![p.name, i])?;
+            // rows keep their position as long as it's still in order
+            let mut last = -1;
+            for t in p.tasks.iter() {
+                let row = Row {
+                    project: p.name.to_owned(),
+                    position: match self.rows.get(&t.id()) {
+                        Some(r) if r.position > last => r.position,
+                        _ => last + 1,
+                    },
+                    data: serde_json::to_string(t)?,
+                };
+                last = row.position;
+                kept.insert(t.id());
+                let unchanged = self.rows
+                    .get(&t.id())
+                    .is_some_and(|r| r.project == row.project && r.position == row.position && r.data == row.data);
+                if unchanged { continue; }
+                tx.execute(
+                    "INSERT INTO tasks (id, project, position, status, priority, data) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                     ON CONFLICT (id) DO UPDATE SET
+                         project = excluded.project,
+                         position = excluded.position,
+                         status = excluded.status,
+                         priority = excluded.priority,
+                         data = excluded.data",
+                    params![t.id(), row.project, row.position, t.status().name(), t.priority().level(), row.data])?;
+                written.push((t.id(), row));
+            }
+        }
+        let removed: Vec<u32> = self.rows.keys().filter(|id| !kept.contains(id)).copied().collect();
+        for id in removed.iter() {
+            tx.execute("DELETE FROM tasks WHERE id = ?1", [id])?;
+        }
+        tx.commit()?;
+        // what's stored now is what was saved, changes made elsewhere are gone
+        self.data_version = self.data_version();
+
+        for id in removed.iter() {
+            self.rows.remove(id);
+        }
+        self.rows.extend(written);
+        Ok(())
+    }
+
+    fn record(&mut self, change: Change) -> Result<(), Box<dyn Error>> {
+        // writing a single task doesn't replace what changed elsewhere, that
+        // still has to be reloaded or merged
+        let external = self.changed_externally();
+        let tx = self.conn.transaction()?;
+        let (id, row) = match change {
+            Change::TaskSaved { project, task } => {
+                tx.execute(
+                    "INSERT OR IGNORE INTO projects (name, position)
+                     VALUES (?1, (SELECT COALESCE(MAX(position), -1) + 1 FROM projects))",
+                    [project])?;
+                // new tasks go last in their project, updated ones keep their place
+                let data = serde_json::to_string(task)?;
+                tx.execute(
+                    "INSERT INTO tasks (id, project, position, status, priority, data)
+                     VALUES (?1, ?2, (SELECT COALESCE(MAX(position), -1) + 1 FROM tasks WHERE project = ?2), ?3, ?4, ?5)
+                     ON CONFLICT (id) DO UPDATE SET
+                         project = excluded.project,
+                         status = excluded.status,
+                         priority = excluded.priority,
+                         data = excluded.data",
+                    params![task.id(), project, task.status().name(), task.priority().level(), data])?;
+                let position: i64 = tx.query_row("SELECT position FROM tasks WHERE id = ?1", [task.id()], |r| r.get(0))?;
+                // so the id isn't handed out again if the rest never gets saved
+                let next_id: Option<String> = tx
+                    .query_row("SELECT value FROM meta WHERE key = 'next_id'", [], |r| r.get(0))
+                    .optional()?;
+                let next_id: u32 = next_id.map(|v| v.parse()).transpose()?.unwrap_or(0);
+                if task.id() >= next_id {
+                    put_meta(&tx, "next_id", &(task.id() + 1))?;
+                }
+                (task.id(), Some(Row { project: project.to_owned(), position, data }))
+            },
+            Change::TaskRemoved(id) => {
+                tx.execute("DELETE FROM tasks WHERE id = ?1", [id])?;
+                (id, None)
+            },
+        };
+        tx.commit()?;
+        if !external {
+            self.data_version = self.data_version();
+        }
+        match row {
+            Some(row) => { self.rows.insert(id, row); },
+            None => { self.rows.remove(&id); },
+        };
+        Ok(())
+    }
+
+    fn path(&self) -> Option<&Path> { Some(&self.path) }
+
+    fn changed_externally(&self) -> bool {
+        self.data_version.is_some() && self.data_version() != self.data_version
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use crate::taskmanager::{Priority, Status};
+
+    fn save(storage: &mut SqliteStorage, tasks: &[Task]) {
+        storage.save(&SaveDataRef {
+            version: crate::schema::CURRENT_VERSION,
+            next_id: tasks.len() as u32,
+            workflow: &Default::default(),
+            priority_scale: &Default::default(),
+            auto_archive_days: None,
+            autosave: Default::default(),
+            active_project: "default",
+            projects: vec![crate::storage::ProjectRef { name: "default", tasks }],
+        }).unwrap();
+    }
+
+    fn task(id: u32, title: &str) -> Task {
+        Task::new(id, &format!("a{:03}", id), title, "", Priority::new(0), Status::new("ToDo"))
+    }
+
+    // counts the rows written to the tasks table from now on
+    fn count_writes(path: &Path) -> Connection {
+        let conn = Connection::open(path).unwrap();
+        conn.execute_batch("
+            CREATE TABLE writes (id INTEGER);
+            CREATE TRIGGER tasks_inserted AFTER INSERT ON tasks BEGIN INSERT INTO writes VALUES (new.id); END;
+            CREATE TRIGGER tasks_updated AFTER UPDATE ON tasks BEGIN INSERT INTO writes VALUES (new.id); END;
+            CREATE TRIGGER tasks_deleted AFTER DELETE ON tasks BEGIN INSERT INTO writes VALUES (old.id); END;
+        ").unwrap();
+        conn
+    }
+
+    fn writes(conn: &Connection) -> Vec<u32> {
+        let mut stmt = conn.prepare("SELECT id FROM writes").unwrap();
+        let ids = stmt.query_map([], |r| r.get(0)).unwrap().map(|id| id.unwrap()).collect();
+        conn.execute("DELETE FROM writes", []).unwrap();
+        ids
+    }
+
+    #[test]
+    fn only_the_changed_tasks_are_written() {
+        let dir = TestDir::new();
+        let path = dir.join("tasks.db");
+        let mut storage = SqliteStorage::open(&path).unwrap();
+        storage.load().unwrap();
+        let mut tasks = vec![task(0, "a"), task(1, "b"), task(2, "c")];
+        save(&mut storage, &tasks);
+        let counter = count_writes(&path);
+        storage.load().unwrap();
+
+        tasks[1] = task(1, "b2");
+        save(&mut storage, &tasks);
+        assert_eq!(writes(&counter), vec![1]);
+        tasks.remove(0);
+        save(&mut storage, &tasks);
+        assert_eq!(writes(&counter), vec![0]);
+        tasks[1] = task(2, "c2");
+        storage.record(Change::TaskSaved { project: "default", task: &tasks[1] }).unwrap();
+        assert_eq!(writes(&counter), vec![2]);
+        save(&mut storage, &tasks);
+        assert!(writes(&counter).is_empty());
+    }
+
+    #[test]
+    fn overwriting_external_changes_settles_them() {
+        let dir = TestDir::new();
+        let path = dir.join("tasks.db");
+        let mut storage = SqliteStorage::open(&path).unwrap();
+        storage.load().unwrap();
+        save(&mut storage, &[task(0, "a")]);
+        assert!(!storage.changed_externally());
+
+        let other = Connection::open(&path).unwrap();
+        other.execute("DELETE FROM tasks", []).unwrap();
+        assert!(storage.changed_externally());
+        save(&mut storage, &[task(0, "a"), task(1, "b")]);
+        assert!(!storage.changed_externally());
+        // the task deleted elsewhere is written again
+        let (data, _) = storage.load().unwrap();
+        assert_eq!(data.projects[0].tasks.len(), 2);
+    }
+}
@@ -0,0 +1,464 @@
+use std::{
+    error::Error,
+    fs::{self, OpenOptions},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Local};
+
+use super::taskmanager::{Autosave, Task, DEFAULT_PROJECT};
+use super::workflow::Workflow;
+use super::scale::PriorityScale;
+use super::backup::{self, Backup};
+use super::schema;
+use super::lock::FileLock;
+use super::crypto::{self, Cipher};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Project {
+    pub name: String,
+    pub tasks: Vec<Task>,
+}
+
+#[derive(Serialize)]
+pub struct ProjectRef<'t> {
+    pub name: &'t str,
+    pub tasks: &'t [Task],
+}
+
+// everything a task file holds: the id counter is persisted alongside the
+// tasks so that ids are never handed out twice
+#[derive(Deserialize, Default, Clone)]
+pub struct SaveData {
+    pub next_id: u32,
+    #[serde(default)]
+    pub workflow: Workflow,
+    #[serde(default)]
+    pub priority_scale: PriorityScale,
+    #[serde(default)]
+    pub auto_archive_days: Option<u32>,
+    #[serde(default)]
+    pub autosave: Autosave,
+    #[serde(default)]
+    pub active_project: Option<String>,
+    #[serde(default)]
+    pub projects: Vec<Project>,
+}
+
+#[derive(Serialize)]
+pub struct SaveDataRef<'t> {
+    pub version: u64,
+    pub next_id: u32,
+    pub workflow: &'t Workflow,
+    pub priority_scale: &'t PriorityScale,
+    pub auto_archive_days: Option<u32>,
+    pub autosave: Autosave,
+    pub active_project: &'t str,
+    pub projects: Vec<ProjectRef<'t>>,
+}
+
+impl SaveData {
+    pub fn borrowed(&self) -> SaveDataRef<'_> {
+        SaveDataRef {
+            version: schema::CURRENT_VERSION,
+            next_id: self.next_id,
+            workflow: &self.workflow,
+            priority_scale: &self.priority_scale,
+            auto_archive_days: self.auto_archive_days,
+            autosave: self.autosave,
+            active_project: self.active_project.as_deref().unwrap_or(DEFAULT_PROJECT),
+            projects: self.projects
+                .iter()
+                .map(|p| ProjectRef { name: &p.name, tasks: &p.tasks })
+                .collect(),
+        }
+    }
+}
+
+/// A single change, for storages that write changes as they happen.
+pub enum Change<'t> {
+    // a new or modified task
+    TaskSaved { project: &'t str, task: &'t Task },
+    TaskRemoved(u32),
+}
+
+/// A task as it was saved at some point, None if it was removed then.
+#[derive(Debug, Clone)]
+pub struct TaskVersion {
+    pub at: DateTime<Local>,
+    pub task: Option<Task>,
+}
+
+/// Where a TaskManager's data is kept between runs.
+pub trait Storage {
+    /// Reads the stored data, along with warnings about it (e.g. that it was
+    /// upgraded from an older format).
+    fn load(&mut self) -> Result<(SaveData, Vec<String>), String>;
+
+    fn save(&mut self, data: &SaveDataRef) -> Result<(), Box<dyn Error>>;
+
+    /// Writes a single change right away. Storages that can only write
+    /// everything at once ignore it and wait for `save`.
+    fn record(&mut self, _change: Change) -> Result<(), Box<dyn Error>> { Ok(()) }
+
+    /// The file the data lives in, related files (like the archive) are
+    /// kept next to it. None if nothing is written to disk.
+    fn path(&self) -> Option<&Path>;
+
+    /// Earlier versions of the data, most recent first.
+    fn backups(&self) -> Vec<Backup> { Vec::new() }
+
+    fn restore_backup(&mut self, backup: &Backup) -> Result<(), String> {
+        Err(format!("can't restore '{}', this storage keeps no backups...", backup.path.display()))
+    }
+
+    /// Whether something else changed the stored data since it was last
+    /// loaded or saved.
+    fn changed_externally(&self) -> bool { false }
+
+    /// The data as it was last loaded or saved, the common ancestor when
+    /// merging external changes. None if it isn't kept.
+    fn base(&self) -> Option<SaveData> { None }
+
+    /// Every version a task was saved in, oldest first. Only storages that
+    /// keep their changes have it.
+    fn task_history(&self, _id: u32) -> Result<Vec<TaskVersion>, String> {
+        Err("this storage keeps no history, use '--storage journal' for it...".to_string())
+    }
+
+    /// Encrypts the stored data with the passphrase from the next save
+    /// on, or stops encrypting it. Existing backups are rewritten to match
+    /// right away.
+    fn set_passphrase(&mut self, _passphrase: Option<&str>) -> Result<(), String> {
+        Err("this storage can't be encrypted, only JSON files can...".to_string())
+    }
+
+    /// What the stored data is encrypted with, files kept next to it (like
+    /// the archive) use it too.
+    fn cipher(&self) -> Option<&Cipher> { None }
+}
+
+/// Copies everything stored in `from` into `to`, which has to be empty.
+/// Returns the number of tasks copied.
+pub fn import(from: &mut dyn Storage, to: &mut dyn Storage) -> Result<usize, String> {
+    let (existing, _) = to.load()?;
+    if existing.projects.iter().any(|p| !p.tasks.is_empty()) {
+        return Err("the target already holds tasks, import into an empty one...".to_string());
+    }
+    let (data, _) = from.load()?;
+    to.save(&data.borrowed())
+        .map_err(|e| format!("could not write imported tasks: {}...", e))?;
+    Ok(data.projects.iter().map(|p| p.tasks.len()).sum())
+}
+
+
+
+/// Whether two values would be written the same way. Tasks don't implement
+/// `PartialEq` (their derived fields would get in the way), and what gets
+/// written is what matters when comparing them anyway.
+pub(crate) fn same<T: Serialize + ?Sized>(a: &T, b: &T) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+// ..:: JsonStorage ::..
+
+/// The whole task file as a single JSON document, rewritten on every save
+/// and encrypted if it has a passphrase. The file is locked for as long as
+/// the storage exists.
+pub struct JsonStorage {
+    path: PathBuf,
+    // the file's (decrypted) content as last loaded or saved
+    base: Option<String>,
+    // the file as last loaded or saved, to tell whether it changed since
+    on_disk: Option<Vec<u8>>,
+    cipher: Option<Cipher>,
+    // the file is backed up on the first save after loading it rather than
+    // on every save, so autosaves don't rotate the older backups away
+    backed_up: bool,
+    _lock: FileLock,
+}
+
+impl JsonStorage {
+    pub fn open(path: &Path) -> Result<JsonStorage, String> {
+        Ok(JsonStorage { path: path.to_owned(), base: None, on_disk: None, cipher: None, backed_up: false, _lock: FileLock::acquire(path)? })
+    }
+
+    /// Whether the file on disk is encrypted, it can't be loaded before
+    /// it's unlocked then.
+    pub fn is_encrypted(&self) -> bool {
+        fs::read(&self.path).map(|c| crypto::is_encrypted(&c)).unwrap_or(false)
+    }
+
+    // copies the file to a new backup, encrypted if the file will be
+    fn back_up(&self) -> io::Result<()> {
+        let content = match fs::read(&self.path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => { return Ok(()); },
+            Err(e) => { return Err(e); },
+        };
+        let content = match &self.cipher {
+            Some(cipher) if !crypto::is_encrypted(&content) => cipher
+                .encrypt(&content)
+                .map_err(|e| io::Error::other(e.to_string()))?,
+            _ => content,
+        };
+        backup::create(&self.path, &content, backup::BACKUPS_KEPT)
+    }
+
+    // rewrites the backups the way the file is now stored, so no plaintext
+    // copy stays behind once it's encrypted; backups that `old` can't
+    // decrypt (encrypted with an even older passphrase) are removed
+    fn reencrypt_backups(&self, mut old: Option<Cipher>) -> Result<(), String> {
+        for b in backup::list(&self.path) {
+            let failed = |e: String| format!("could not rewrite backup '{}': {}...", b.path.display(), e);
+            let content = fs::read(&b.path).map_err(|e| failed(e.to_string()))?;
+            let plaintext = match (&mut old, crypto::is_encrypted(&content)) {
+                (_, false) => Some(content),
+                (Some(old), true) => old.decrypt(&content).ok(),
+                (None, true) => None,
+            };
+            let res = match (plaintext, &self.cipher) {
+                (Some(p), Some(cipher)) => cipher
+                    .encrypt(&p)
+                    .map_err(|e| e.to_string())
+                    .and_then(|c| backup::write_atomic(&b.path, &c).map_err(|e| e.to_string())),
+                (Some(p), None) => backup::write_atomic(&b.path, &p).map_err(|e| e.to_string()),
+                (None, _) => fs::remove_file(&b.path).map_err(|e| e.to_string()),
+            };
+            res.map_err(failed)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the passphrase against the encrypted file and keeps it for
+    /// loading and saving.
+    pub fn unlock(&mut self, passphrase: &str) -> Result<(), String> {
+        let content = fs::read(&self.path)
+            .map_err(|e| format!("could not read file '{}': {}...", self.path.display(), e))?;
+        let cipher = Cipher::for_content(passphrase, &content)
+            .and_then(|mut c| c.decrypt(&content).map(|_| c))
+            .map_err(|e| format!("can't open file '{}': {}...", self.path.display(), e))?;
+        self.cipher = Some(cipher);
+        Ok(())
+    }
+}
+
+impl Storage for JsonStorage {
+    fn load(&mut self) -> Result<(SaveData, Vec<String>), String> {
+        let mut f = OpenOptions::new()
+            .write(true)
+            .read(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)
+            .ok()
+            .ok_or(format!("could not open file '{}'...", self.path.display()))?;
+        let mut content = Vec::new();
+        f.read_to_end(&mut content)
+            .ok().ok_or("could not read file to buffer...".to_string())?;
+        drop(f);
+
+        let mut warnings = Vec::new();
+        let plaintext = match (&mut self.cipher, crypto::is_encrypted(&content)) {
+            (Some(cipher), true) => cipher.decrypt(&content)
+                .map_err(|e| format!("can't open file '{}': {}...", self.path.display(), e))?,
+            (None, true) => {
+                return Err(format!("file '{}' is encrypted, start taskman again to enter its passphrase...", self.path.display()));
+            },
+            (Some(_), false) => {
+                if !content.iter().all(|b| b.is_ascii_whitespace()) {
+                    warnings.push(format!("file '{}' isn't encrypted yet, it will be on the next save...", self.path.display()));
+                }
+                content.clone()
+            },
+            (None, false) => content.clone(),
+        };
+        let buffer = String::from_utf8(plaintext)
+            .ok().ok_or(format!("file '{}' isn't a task file...", self.path.display()))?;
+
+        let (data, version) = schema::load(&buffer)
+            .map_err(|e| format!("couldn't load file '{}': {}", self.path.display(), e))?;
+        self.base = Some(buffer.clone());
+        self.on_disk = Some(content);
+        self.backed_up = false;
+        if version < schema::CURRENT_VERSION && !buffer.trim().is_empty() {
+            warnings.push(format!("upgraded file from version {} to {}, the old one is backed up on save...",
+                version, schema::CURRENT_VERSION));
+        }
+        Ok((data, warnings))
+    }
+
+    fn save(&mut self, data: &SaveDataRef) -> Result<(), Box<dyn Error>> {
+        let serialized = serde_json::to_string(data)?;
+        let content = match &self.cipher {
+            Some(cipher) => cipher.encrypt(serialized.as_bytes()).map_err(|e| e.to_string())?,
+            None => serialized.clone().into_bytes(),
+        };
+        if !self.backed_up {
+            self.back_up()?;
+            self.backed_up = true;
+        }
+        backup::write_atomic(&self.path, &content)?;
+        self.base = Some(serialized);
+        self.on_disk = Some(content);
+        Ok(())
+    }
+
+    fn path(&self) -> Option<&Path> { Some(&self.path) }
+
+    fn backups(&self) -> Vec<Backup> { backup::list(&self.path) }
+
+    fn restore_backup(&mut self, chosen: &Backup) -> Result<(), String> {
+        // the current content is backed up first, so a restore can be undone
+        // by restoring again
+        fs::read(&chosen.path)
+            .and_then(|content| {
+                self.back_up()?;
+                backup::write_atomic(&self.path, &content)
+            })
+            .map_err(|e| format!("could not restore backup '{}': {}...", chosen.path.display(), e))
+    }
+
+    fn changed_externally(&self) -> bool {
+        match &self.on_disk {
+            Some(on_disk) => fs::read(&self.path).map(|c| c != *on_disk).unwrap_or(true),
+            None => false,
+        }
+    }
+
+    fn base(&self) -> Option<SaveData> {
+        schema::load(self.base.as_ref()?).ok().map(|(data, _)| data)
+    }
+
+    fn set_passphrase(&mut self, passphrase: Option<&str>) -> Result<(), String> {
+        let cipher = match passphrase {
+            Some(p) => Some(Cipher::new(p).map_err(|e| format!("{}...", e))?),
+            None => None,
+        };
+        let old = std::mem::replace(&mut self.cipher, cipher);
+        self.reencrypt_backups(old)
+    }
+
+    fn cipher(&self) -> Option<&Cipher> { self.cipher.as_ref() }
+}
+
+
+
+// ..:: MemoryStorage ::..
+
+/// Keeps the data in memory only, everything is lost on exit. Saves go
+/// through the same serialization as a JSON file.
+#[derive(Default)]
+pub struct MemoryStorage {
+    content: String,
+}
+
+#[cfg(test)]
+impl MemoryStorage {
+    /// A storage holding what a task file would, in any version.
+    pub fn with_content(content: &str) -> MemoryStorage {
+        MemoryStorage { content: content.to_owned() }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load(&mut self) -> Result<(SaveData, Vec<String>), String> {
+        let (data, _) = schema::load(&self.content)
+            .map_err(|e| format!("couldn't load stored data: {}", e))?;
+        Ok((data, Vec::new()))
+    }
+
+    fn save(&mut self, data: &SaveDataRef) -> Result<(), Box<dyn Error>> {
+        self.content = serde_json::to_string(data)?;
+        Ok(())
+    }
+
+    fn path(&self) -> Option<&Path> { None }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use crate::sqlite::SqliteStorage;
+    use crate::journal::JournalStorage;
+    use crate::taskmanager::{Priority, Status, TaskManager, TaskSelector};
+
+    // the tasks of every project as (project, id, title, status)
+    fn summary(tm: &TaskManager) -> Vec<(String, u32, String, String)> {
+        tm.all_projects_tasks()
+            .into_iter()
+            .flat_map(|(p, tasks)| tasks
+                .into_iter()
+                .map(move |t| (p.to_owned(), t.id(), t.title().to_owned(), t.status().name().to_owned())))
+            .collect()
+    }
+
+    #[test]
+    fn every_backend_gives_back_what_was_saved() {
+        let dir = TestDir::new();
+        let backends: Vec<Box<dyn Storage>> = vec![
+            Box::new(MemoryStorage::default()),
+            Box::new(JsonStorage::open(&dir.join("tasks.json")).unwrap()),
+            Box::new(SqliteStorage::open(&dir.join("tasks.db")).unwrap()),
+            Box::new(JournalStorage::open(&dir.join("tasks.journal")).unwrap()),
+        ];
+        let mut summaries = Vec::new();
+        for storage in backends {
+            let mut tm = TaskManager::new(storage);
+            tm.load().unwrap();
+            let a = tm.new_task("a", "", Priority::new(1), Status::new("ToDo"));
+            let b = tm.new_task("b", "", Priority::new(0), Status::new("ToDo"));
+            tm.save().unwrap();
+            tm.change_task_status(TaskSelector::Id(a), Status::new("Doing")).unwrap();
+            tm.remove_task(TaskSelector::Id(b));
+            tm.switch_project("work");
+            tm.new_task("c", "", Priority::new(0), Status::new("ToDo"));
+            tm.save().unwrap();
+
+            let saved = summary(&tm);
+            tm.load().unwrap();
+            assert_eq!(summary(&tm), saved);
+            assert_eq!(tm.project(), "work");
+            // ids aren't handed out twice
+            assert_eq!(tm.new_task("d", "", Priority::new(0), Status::new("ToDo")), 3);
+            summaries.push(saved);
+        }
+        assert!(summaries.windows(2).all(|w| w[0] == w[1]));
+        assert_eq!(summaries[0], vec![
+            ("default".to_owned(), 0, "a".to_owned(), "Doing".to_owned()),
+            ("work".to_owned(), 2, "c".to_owned(), "ToDo".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn the_file_is_backed_up_on_the_first_save_after_loading() {
+        let dir = TestDir::new();
+        let path = dir.join("tasks.json");
+        let mut storage = JsonStorage::open(&path).unwrap();
+        let (mut data, _) = storage.load().unwrap();
+        // nothing to back up yet
+        storage.save(&data.borrowed()).unwrap();
+        assert!(storage.backups().is_empty());
+
+        storage.load().unwrap();
+        data.next_id = 1;
+        storage.save(&data.borrowed()).unwrap();
+        data.next_id = 2;
+        storage.save(&data.borrowed()).unwrap();
+        let backups = storage.backups();
+        assert_eq!(backups.len(), 1);
+        let (backed_up, _): (SaveData, _) = schema::load(&fs::read_to_string(&backups[0].path).unwrap()).unwrap();
+        assert_eq!(backed_up.next_id, 0);
+
+        // backups are told apart by the millisecond they were made in
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        storage.restore_backup(&backups[0]).unwrap();
+        assert_eq!(storage.load().unwrap().0.next_id, 0);
+        assert_eq!(storage.backups().len(), 2);
+    }
+}
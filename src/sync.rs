@@ -0,0 +1,292 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use super::storage::{same, SaveData};
+use super::taskmanager::Task;
+use super::merge::{self, MergeReport};
+use super::schema;
+use super::backup;
+use super::crypto;
+
+/// What a sync did.
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    // the commit message's summary, if there was anything to commit
+    pub committed: Option<String>,
+    // whether changes from the remote were brought in
+    pub pulled: bool,
+    // set if both sides had changes, which were merged task by task
+    pub merged: Option<MergeReport>,
+    pub pushed: bool,
+}
+
+// runs git in the directory of the task file
+struct Git {
+    dir: PathBuf,
+}
+
+impl Git {
+    fn run(&self, args: &[&str]) -> Result<Output, String> {
+        Command::new("git")
+            .args(args)
+            .current_dir(&self.dir)
+            .output()
+            .map_err(|e| format!("could not run git: {}...", e))
+    }
+
+    // the command's output, or what it complained about if it failed
+    fn output(&self, args: &[&str]) -> Result<String, String> {
+        let out = self.run(args)?;
+        if !out.status.success() {
+            return Err(format!("'git {}' failed: {}...",
+                args.join(" "), String::from_utf8_lossy(&out.stderr).trim()));
+        }
+        Ok(String::from_utf8_lossy(&out.stdout).trim_end().to_owned())
+    }
+
+    fn succeeds(&self, args: &[&str]) -> Result<bool, String> {
+        Ok(self.run(args)?.status.success())
+    }
+
+    // the task file as of a commit, empty if it isn't in that commit
+    fn load_at(&self, rev: &str, name: &str) -> Result<SaveData, String> {
+        let out = self.run(&["show", &format!("{}:./{}", rev, name)])?;
+        if !out.status.success() {
+            return Ok(SaveData::default());
+        }
+        if crypto::is_encrypted(&out.stdout) {
+            return Err(format!("'{}' as of {} is encrypted, encrypted task files can't be synced...", name, rev));
+        }
+        schema::load(&String::from_utf8_lossy(&out.stdout))
+            .map(|(data, _)| data)
+            .map_err(|e| format!("couldn't read '{}' as of {}: {}", name, rev, e))
+    }
+}
+
+fn tasks(data: &SaveData) -> HashMap<u32, &Task> {
+    data.projects.iter().flat_map(|p| p.tasks.iter()).map(|t| (t.id(), t)).collect()
+}
+
+/// A commit message for going from `before` to `after`: a summary line
+/// counting the tasks added, changed and removed, then one line per task.
+pub fn describe(before: &SaveData, after: &SaveData) -> (String, Vec<String>) {
+    let old = tasks(before);
+    let new = tasks(after);
+    let mut lines = Vec::<String>::new();
+    let (mut added, mut changed, mut removed) = (0, 0, 0);
+    let mut ids: Vec<u32> = old.keys().chain(new.keys()).copied().collect();
+    ids.sort();
+    ids.dedup();
+    for id in ids {
+        match (old.get(&id), new.get(&id)) {
+            (None, Some(t)) => {
+                added += 1;
+                lines.push(format!("added {} '{}'", id, t.title()));
+            },
+            (Some(o), Some(t)) if !same(o, t) => {
+                changed += 1;
+                lines.push(format!("changed {} '{}'", id, t.title()));
+            },
+            (Some(o), None) => {
+                removed += 1;
+                lines.push(format!("removed {} '{}'", id, o.title()));
+            },
+            _ => { },
+        };
+    }
+    let mut summary = Vec::<String>::new();
+    if added > 0 { summary.push(format!("{} added", added)); }
+    if changed > 0 { summary.push(format!("{} changed", changed)); }
+    if removed > 0 { summary.push(format!("{} removed", removed)); }
+    if summary.is_empty() { summary.push("settings changed".to_string()); }
+    (format!("Sync tasks: {}", summary.join(", ")), lines)
+}
+
+/// Commits the (saved) task file, brings in what's new on the remote and
+/// pushes the result. The task file has to be in a git work tree and its
+/// current branch gets synced with the branch of the same name on
+/// `remote` (by default the branch's remote, or 'origin'). When both
+/// sides changed, the task files are merged task by task rather than line
+/// by line; other files conflicting stops the sync.
+pub fn sync(file: &Path, remote: Option<&str>) -> Result<SyncReport, String> {
+    let name = file
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .ok_or(format!("'{}' is not a file...", file.display()))?;
+    let dir = match file.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d.to_owned(),
+        _ => PathBuf::from("."),
+    };
+    let git = Git { dir };
+    if !git.succeeds(&["rev-parse", "--is-inside-work-tree"])? {
+        return Err(format!("'{}' is not in a git repository...", file.display()));
+    }
+    let branch = git.output(&["symbolic-ref", "--short", "HEAD"])
+        .map_err(|_| "can't sync without a current branch (HEAD is detached)...".to_string())?;
+    let remote = match remote {
+        Some(r) => r.to_owned(),
+        None => git.output(&["config", &format!("branch.{}.remote", branch)]).unwrap_or("origin".to_string()),
+    };
+    let remote = remote.as_str();
+    let mut report = SyncReport::default();
+
+    // commit what changed since the last sync
+    let current = std::fs::read(file)
+        .map_err(|e| format!("could not read '{}': {}...", file.display(), e))?;
+    if crypto::is_encrypted(&current) {
+        return Err(format!("can't sync '{}', encrypted task files can't be synced...", file.display()));
+    }
+    let (current, _) = schema::load::<SaveData>(&String::from_utf8_lossy(&current))
+        .map_err(|e| format!("can't sync '{}', only JSON task files can be synced: {}", file.display(), e))?;
+    git.output(&["add", "--", &name])?;
+    if !git.succeeds(&["diff", "--cached", "--quiet", "--", &name])? {
+        let has_head = git.succeeds(&["rev-parse", "--verify", "--quiet", "HEAD"])?;
+        let previous = if has_head { git.load_at("HEAD", &name)? } else { SaveData::default() };
+        let (summary, lines) = describe(&previous, &current);
+        git.output(&["commit", "-m", &summary, "-m", &lines.join("\n"), "--", &name])?;
+        report.committed = Some(summary);
+    }
+
+    // bring in the remote's changes
+    git.output(&["fetch", remote])?;
+    let theirs_ref = format!("{}/{}", remote, branch);
+    let has_theirs = git.succeeds(&["rev-parse", "--verify", "--quiet", &format!("refs/remotes/{}", theirs_ref)])?;
+    if has_theirs && !git.succeeds(&["merge-base", "--is-ancestor", &theirs_ref, "HEAD"])? {
+        report.pulled = true;
+        if git.succeeds(&["merge-base", "--is-ancestor", "HEAD", &theirs_ref])? {
+            git.output(&["merge", "--ff-only", &theirs_ref])?;
+        } else {
+            report.merged = Some(merge_diverged(&git, file, &name, &theirs_ref)?);
+        }
+    }
+
+    git.output(&["push", "-u", remote, "HEAD"])?;
+    report.pushed = true;
+    Ok(report)
+}
+
+// merges the remote branch into the current one, with the task file
+// merged task by task
+fn merge_diverged(git: &Git, file: &Path, name: &str, theirs_ref: &str) -> Result<MergeReport, String> {
+    // histories started separately (e.g. in clones of an empty repository)
+    // have no common ancestor, all the tasks are new to both sides then
+    let base = match git.output(&["merge-base", "HEAD", theirs_ref]) {
+        Ok(base_rev) => git.load_at(&base_rev, name)?,
+        Err(_) => SaveData::default(),
+    };
+    let ours = git.load_at("HEAD", name)?;
+    let theirs = git.load_at(theirs_ref, name)?;
+
+    // the task file conflicting is expected (it's a single line), anything
+    // else is left to be merged by hand
+    let _ = git.run(&["merge", "--no-ff", "--no-commit", "--allow-unrelated-histories", theirs_ref])?;
+    let unmerged = git.output(&["diff", "--name-only", "--diff-filter=U", "--relative"])?;
+    let others: Vec<&str> = unmerged.lines().filter(|f| *f != name).collect();
+    if !others.is_empty() {
+        let _ = git.run(&["merge", "--abort"]);
+        return Err(format!("merging '{}' conflicts in {}, merge it with git...", theirs_ref, others.join(", ")));
+    }
+    if !git.succeeds(&["rev-parse", "--verify", "--quiet", "MERGE_HEAD"])? {
+        return Err(format!("could not merge '{}'...", theirs_ref));
+    }
+
+    let merged = merge::merge(&base, &ours, &theirs);
+    let content = serde_json::to_string(&merged.data.borrowed())
+        .map_err(|e| format!("could not write the merged tasks: {}...", e))?;
+    backup::write_atomic(file, content.as_bytes())
+        .map_err(|e| format!("could not write the merged tasks: {}...", e))?;
+    git.output(&["add", "--", name])?;
+    let mut lines: Vec<String> = merged.report.conflicts
+        .iter()
+        .map(|c| format!("conflict on task {} ({}), marked for resolution", c.id, c.fields.join(", ")))
+        .collect();
+    lines.extend(merged.report.renumbered.iter().map(|(old, new)| format!("local task {} became {}", old, new)));
+    git.output(&["commit", "-m", &format!("Merge tasks from {}", theirs_ref), "-m", &lines.join("\n")])?;
+    Ok(merged.report)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use crate::storage::Project;
+    use crate::taskmanager::{Priority, Status, DEFAULT_PROJECT};
+
+    fn git(dir: &Path, args: &[&str]) {
+        Git { dir: dir.to_owned() }.output(args).unwrap();
+    }
+
+    // a clone of `remote` that can commit
+    fn clone(remote: &Path, dir: &Path) {
+        git(remote.parent().unwrap(), &["clone", "-q", remote.to_str().unwrap(), dir.to_str().unwrap()]);
+        git(dir, &["config", "user.name", "taskman"]);
+        git(dir, &["config", "user.email", "taskman@localhost"]);
+        git(dir, &["config", "commit.gpgsign", "false"]);
+        git(dir, &["symbolic-ref", "HEAD", "refs/heads/main"]);
+    }
+
+    fn write(file: &Path, tasks: &[(u32, &str)]) {
+        let data = SaveData {
+            next_id: tasks.len() as u32,
+            projects: vec![Project {
+                name: DEFAULT_PROJECT.to_owned(),
+                tasks: tasks
+                    .iter()
+                    .map(|(id, title)| Task::new(*id, &format!("a{:03}", id), title, "", Priority::new(0), Status::new("ToDo")))
+                    .collect(),
+            }],
+            ..Default::default()
+        };
+        std::fs::write(file, serde_json::to_string(&data.borrowed()).unwrap()).unwrap();
+    }
+
+    // changes a task's title in the file, leaving everything else as it is
+    fn rename(file: &Path, id: usize, title: &str) {
+        let mut content: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(file).unwrap()).unwrap();
+        content["projects"][0]["tasks"][id]["title"] = title.into();
+        std::fs::write(file, content.to_string()).unwrap();
+    }
+
+    fn titles(file: &Path) -> Vec<String> {
+        let (data, _) = schema::load::<SaveData>(&std::fs::read_to_string(file).unwrap()).unwrap();
+        data.projects.iter().flat_map(|p| p.tasks.iter().map(|t| t.title().to_owned())).collect()
+    }
+
+    #[test]
+    fn changes_on_both_sides_are_merged_task_by_task() {
+        let dir = TestDir::new();
+        let remote = dir.join("remote.git");
+        git(dir.path(), &["init", "-q", "--bare", remote.to_str().unwrap()]);
+        git(&remote, &["symbolic-ref", "HEAD", "refs/heads/main"]);
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        clone(&remote, &a);
+        clone(&remote, &b);
+        let (file_a, file_b) = (a.join("tasks.json"), b.join("tasks.json"));
+
+        write(&file_a, &[(0, "first"), (1, "second")]);
+        let report = sync(&file_a, None).unwrap();
+        assert_eq!(report.committed.as_deref(), Some("Sync tasks: 2 added"));
+        assert!(report.pushed && !report.pulled);
+        write(&file_b, &[]);
+        let report = sync(&file_b, None).unwrap();
+        assert!(report.pulled && report.merged.is_some());
+        assert_eq!(titles(&file_b), vec!["first", "second"]);
+
+        // each side changes a different task
+        rename(&file_a, 0, "first, from a");
+        sync(&file_a, None).unwrap();
+        rename(&file_b, 1, "second, from b");
+        let report = sync(&file_b, None).unwrap();
+        assert!(report.merged.unwrap().conflicts.is_empty());
+        assert_eq!(titles(&file_b), vec!["first, from a", "second, from b"]);
+        // and the merge went back to the remote
+        let report = sync(&file_a, None).unwrap();
+        assert!(report.committed.is_none() && report.pulled && report.merged.is_none());
+        assert_eq!(titles(&file_a), titles(&file_b));
+    }
+}
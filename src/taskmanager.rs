@@ -1,42 +1,82 @@
 #![allow(dead_code)]
 
 use std::{
+    collections::{BTreeSet, HashMap, HashSet, hash_map::DefaultHasher},
     fmt::Display,
+    hash::{Hash, Hasher},
     str::FromStr,
-    fs::OpenOptions,
-    io::{Write, Read},
-    path::Path,
+    io::Write,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Duration, Local, NaiveDate};
+
+use super::dates;
+use super::recurrence::Recurrence;
+use super::workflow::{StatusDef, Workflow, WorkflowError};
+use super::scale::{PriorityScale, ScaleError};
+use super::archive::{Archive, ArchivedTask};
+use super::backup::Backup;
+use super::schema;
+use super::storage::{self, Storage, Change, TaskVersion, Project, ProjectRef, SaveData, SaveDataRef};
+use super::merge::{self, FieldConflict, MergeReport, Side};
+use super::history::{History, OPERATIONS_KEPT};
+use super::sync::{self, SyncReport};
 
 
 
 // ..:: Priority ::..
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub enum Priority {
-    Low,
-    Medium,
-    High,
+/// A level on the task file's priority scale, 0 being the lowest.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[serde(transparent)]
+pub struct Priority(u8);
+
+impl Priority {
+    pub fn new(level: u8) -> Priority { Priority(level) }
+    pub fn level(&self) -> u8 { self.0 }
+
+    /// Star bar as wide as the scale, e.g. '[**   ]' for level 1 of 5.
+    pub fn bar(&self, levels: usize) -> String {
+        let stars = (self.0 as usize + 1).min(levels.max(1));
+        format!("[{}{}]", "*".repeat(stars), " ".repeat(levels.max(stars) - stars))
+    }
+}
+
+impl Display for Priority {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0 + 1)
+    }
 }
 
-impl Default for Priority {
-    fn default() -> Self { Priority::Low }
+// files written before the priority scale existed store Low/Medium/High
+impl<'de> Deserialize<'de> for Priority {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Level(u8),
+            Name(String),
+        }
+        match Repr::deserialize(deserializer)? {
+            Repr::Level(level) => Ok(Priority(level)),
+            Repr::Name(name) => PriorityScale::default()
+                .resolve(&name)
+                .ok_or(serde::de::Error::custom(format!("unknown priority '{}'", name))),
+        }
+    }
 }
 
 pub struct ParsePriorityError;
 
+/// Accepts the default level names (low, medium, high) or a level number,
+/// 1 being the lowest. Use `PriorityScale::resolve` for custom scales.
 impl FromStr for Priority {
     type Err=ParsePriorityError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
-            "low" => Ok(Self::Low),
-            "medium" => Ok(Self::Medium),
-            "high" => Ok(Self::High),
-            _ => Err(ParsePriorityError),
-        }
+        PriorityScale::default().resolve(s).ok_or(ParsePriorityError)
     }
 }
 
@@ -44,15 +84,21 @@ impl FromStr for Priority {
 
 // ..:: Status ::..
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub enum Status {
-    ToDo,
-    Doing,
-    Done,
+/// A task's status, one of those defined by the task file's workflow
+/// (ToDo, Doing and Done by default).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(transparent)]
+pub struct Status(String);
+
+impl Status {
+    pub fn new(name: &str) -> Status { Status(name.to_owned()) }
+    pub fn name(&self) -> &str { &self.0 }
 }
 
-impl Default for Status {
-    fn default() -> Self { Status::ToDo }
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
 pub struct ParseStatusError;
@@ -61,59 +107,300 @@ impl FromStr for Status {
     type Err=ParseStatusError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
-            "todo" => Ok(Self::ToDo),
-            "doing" => Ok(Self::Doing),
-            "done" => Ok(Self::Done),
-            _ => Err(ParseStatusError),
+        if s.is_empty() || s.contains(char::is_whitespace) {
+            return Err(ParseStatusError);
+        }
+        Ok(Self::new(s))
+    }
+}
+
+
+
+// ..:: WorkInterval ::..
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct WorkInterval {
+    start: DateTime<Local>,
+    // None while the timer is still running
+    end: Option<DateTime<Local>>,
+}
+
+impl WorkInterval {
+    fn duration(&self, now: DateTime<Local>) -> Duration {
+        self.end.unwrap_or(now) - self.start
+    }
+}
+
+
+
+// ..:: Activity ::..
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum Activity {
+    Note(String),
+    StatusChanged(Status, Status),
+    PriorityChanged(Priority, Priority),
+    DescriptionChanged(String, String),
+}
+
+impl Activity {
+    // priorities are stored as levels, shown with the scale's names
+    fn describe(&self, scale: &PriorityScale) -> String {
+        match self {
+            Activity::Note(text) => text.clone(),
+            Activity::StatusChanged(from, to) => format!("status: {} -> {}", from, to),
+            Activity::PriorityChanged(from, to) => format!("priority: {} -> {}", scale.name(*from), scale.name(*to)),
+            Activity::DescriptionChanged(from, to) => format!("description: \"{}\" -> \"{}\"", from, to),
         }
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ActivityEntry {
+    at: DateTime<Local>,
+    activity: Activity,
+}
+
 
 
 // ..:: Task ::..
 
 #[allow(dead_code)]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Task {
     id: u32,
+    #[serde(default)]
+    hash: String,
     title: String,
     description: String,
     priority: Priority,
     status: Status,
+    #[serde(default)]
+    due: Option<NaiveDate>,
+    #[serde(default)]
+    scheduled: Option<NaiveDate>,
+    #[serde(default)]
+    completed_at: Option<DateTime<Local>>,
+    #[serde(default)]
+    tags: BTreeSet<String>,
+    #[serde(default)]
+    parent: Option<u32>,
+    // (done, total) direct subtasks, kept up to date by the TaskManager
+    #[serde(skip)]
+    progress: Option<(usize, usize)>,
+    #[serde(default)]
+    depends_on: BTreeSet<u32>,
+    // whether any of the dependencies isn't done yet, kept up to date by the TaskManager
+    #[serde(skip)]
+    blocked: bool,
+    // number of levels in the priority scale, kept up to date by the TaskManager
+    #[serde(skip)]
+    scale_levels: usize,
+    #[serde(default)]
+    recurrence: Option<Recurrence>,
+    // the completed occurrence this task was spawned from
+    #[serde(default)]
+    previous: Option<u32>,
+    #[serde(default)]
+    intervals: Vec<WorkInterval>,
+    // append-only
+    #[serde(default)]
+    activity: Vec<ActivityEntry>,
+    // missing in tasks saved before timestamps were recorded
+    #[serde(default)]
+    created_at: Option<DateTime<Local>>,
+    #[serde(default)]
+    updated_at: Option<DateTime<Local>>,
+    // what a merge couldn't decide, until it's resolved
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    conflicts: Vec<FieldConflict>,
 }
 
 impl Display for Task {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f,
-            "{}. {} {}",
+            "{}. {} {}{}{}{}",
             self.id,
-            match self.priority {
-                Priority::Low => "[*  ]",
-                Priority::Medium => "[** ]",
-                Priority::High => "[***]",
-            },
+            self.priority.bar(self.scale_levels),
+            if self.conflicts.is_empty() { "" } else { "(conflict) " },
+            if self.blocked { "(blocked) " } else { "" },
+            if self.is_overdue(dates::today()) { "!! " } else { "" },
             self.title,
-        )
+        )?;
+        if let Some((done, total)) = self.progress {
+            write!(f, " {}/{}", done, total)?;
+        }
+        if let (Some(due), None) = (self.due, self.completed_at) {
+            write!(f, " (due {})", due.format("%m-%d"))?;
+        }
+        if let Some(since) = self.running_since() {
+            write!(f, " [running {}]", dates::format_duration(Local::now() - since))?;
+        }
+        Ok(())
     }
 }
 
 impl Task {
-    pub fn new(id: u32, title: &str, description: &str, priority: Priority, status: Status) -> Task {
+    pub fn new(id: u32, hash: &str, title: &str, description: &str, priority: Priority, status: Status) -> Task {
+        let now = Local::now();
         Task {
             id,
+            hash: hash.to_owned(),
             title: title.to_owned(),
             description: description.to_owned(),
             priority,
             status,
+            due: None,
+            scheduled: None,
+            completed_at: None,
+            tags: BTreeSet::new(),
+            parent: None,
+            progress: None,
+            depends_on: BTreeSet::new(),
+            blocked: false,
+            scale_levels: 0,
+            recurrence: None,
+            previous: None,
+            intervals: Vec::new(),
+            activity: Vec::new(),
+            created_at: Some(now),
+            updated_at: Some(now),
+            conflicts: Vec::new(),
         }
     }
 
     pub fn id(&self) -> u32 { self.id }
+    pub fn title(&self) -> &str { &self.title }
+    pub fn description(&self) -> &str { &self.description }
+    pub fn hash(&self) -> &str { &self.hash }
+
+    pub fn completed_at(&self) -> Option<DateTime<Local>> { self.completed_at }
+
+    pub fn status(&self) -> &Status { &self.status }
+    pub fn priority(&self) -> Priority { self.priority }
+    pub fn parent(&self) -> Option<u32> { self.parent }
+    pub fn tags(&self) -> &BTreeSet<String> { &self.tags }
+    pub fn depends_on(&self) -> &BTreeSet<u32> { &self.depends_on }
+    pub fn is_blocked(&self) -> bool { self.blocked }
+
+    pub fn updated_at(&self) -> Option<DateTime<Local>> { self.updated_at }
+
+    pub fn conflicts(&self) -> &[FieldConflict] { &self.conflicts }
+
+    /// Marks a field as conflicting, replacing an earlier conflict on it.
+    pub fn mark_conflict(&mut self, conflict: FieldConflict) {
+        self.conflicts.retain(|c| c.field != conflict.field);
+        self.conflicts.push(conflict);
+    }
+
+    fn touch(&mut self) {
+        self.updated_at = Some(Local::now());
+    }
+
+    /// Gives the task (or the tasks it refers to) new ids, e.g. when merging
+    /// two files that handed out the same id.
+    pub fn renumber(&mut self, ids: &HashMap<u32, u32>) {
+        let map = |id: u32| *ids.get(&id).unwrap_or(&id);
+        self.id = map(self.id);
+        self.parent = self.parent.map(map);
+        self.previous = self.previous.map(map);
+        self.depends_on = self.depends_on.iter().map(|d| map(*d)).collect();
+    }
+
+    fn record(&mut self, activity: Activity) {
+        self.activity.push(ActivityEntry { at: Local::now(), activity });
+        self.touch();
+    }
+
+    pub fn running_since(&self) -> Option<DateTime<Local>> {
+        self.intervals
+            .last()
+            .filter(|i| i.end.is_none())
+            .map(|i| i.start)
+    }
+
+    /// Total time recorded on the task, including a running timer.
+    pub fn tracked_time(&self) -> Duration {
+        let now = Local::now();
+        self.intervals
+            .iter()
+            .fold(Duration::zero(), |acc, i| acc + i.duration(now))
+    }
+
+    // closes the running interval, if any
+    fn stop_timer(&mut self) -> bool {
+        match self.intervals.last_mut() {
+            Some(i) if i.end.is_none() => {
+                i.end = Some(Local::now());
+                true
+            },
+            _ => false,
+        }
+    }
+    pub fn has_tag(&self, tag: &str) -> bool { self.tags.contains(tag) }
+
+    pub fn is_overdue(&self, today: NaiveDate) -> bool {
+        match self.due {
+            Some(due) => due < today && self.completed_at.is_none(),
+            None => false,
+        }
+    }
 
-    pub fn log(&self) -> String {
-        format!("{}\n{}", self, self.description)
+    pub fn log(&self, scale: &PriorityScale) -> String {
+        let mut info = String::new();
+        if let Some(scheduled) = self.scheduled {
+            info.push_str(&format!("scheduled: {}\n", scheduled));
+        }
+        if let Some(due) = self.due {
+            info.push_str(&format!("due: {}{}\n", due,
+                if self.is_overdue(dates::today()) { " (overdue)" } else { "" }));
+        }
+        if !self.tags.is_empty() {
+            info.push_str(&format!("tags: {}\n", self.tags
+                .iter()
+                .map(|t| format!("+{}", t))
+                .collect::<Vec<String>>()
+                .join(" ")));
+        }
+        if !self.depends_on.is_empty() {
+            info.push_str(&format!("depends on: {}{}\n", self.depends_on
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<String>>()
+                .join(", "),
+                if self.blocked { " (blocked)" } else { "" }));
+        }
+        if let Some(recurrence) = &self.recurrence {
+            info.push_str(&format!("repeats: {}\n", recurrence));
+        }
+        if !self.intervals.is_empty() {
+            info.push_str(&format!("tracked: {}{}\n",
+                dates::format_duration(self.tracked_time()),
+                match self.running_since() {
+                    Some(since) => format!(" (running since {})", since.format("%H:%M")),
+                    None => String::new(),
+                }));
+        }
+        if let Some(completed_at) = self.completed_at {
+            info.push_str(&format!("completed: {}\n", completed_at.format("%Y-%m-%d %H:%M")));
+        }
+        for c in self.conflicts.iter() {
+            info.push_str(&format!("conflict: {} is {} here, {} on the other side\n", c.field, c.ours, c.theirs));
+        }
+        if let Some(created_at) = self.created_at {
+            info.push_str(&format!("created: {}\n", created_at.format("%Y-%m-%d %H:%M")));
+        }
+        if let Some(updated_at) = self.updated_at {
+            info.push_str(&format!("updated: {}\n", updated_at.format("%Y-%m-%d %H:%M")));
+        }
+        let mut timeline = String::new();
+        if !self.activity.is_empty() {
+            timeline.push_str("\nactivity:");
+            for entry in self.activity.iter() {
+                timeline.push_str(&format!("\n{}  {}", entry.at.format("%Y-%m-%d %H:%M"), entry.activity.describe(scale)));
+            }
+        }
+        format!("{} (#{})\n{}{}{}", self, self.hash, info, self.description, timeline)
     }
 }
 
@@ -127,6 +414,62 @@ pub enum TaskSelector {
 #[derive(Debug, PartialEq, Eq)]
 pub struct TaskNotFountError;
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum StatusError {
+    TaskNotFound,
+    UnknownStatus,
+    NotAllowed(Status, Status),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DependencyError {
+    TaskNotFound,
+    DependencyNotFound,
+    Cycle,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ArchiveError {
+    Unavailable,
+    TaskNotFound,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConflictError {
+    TaskNotFound,
+    // the task has no conflict (on the given field)
+    NoConflict,
+    // the chosen value would make the task its own ancestor or dependency
+    Cycle,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HistoryError {
+    Empty,
+    // the operation was done in another project, which has to be active
+    OtherProject(String),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MoveError {
+    TaskNotFound,
+    ProjectNotFound,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParentError {
+    TaskNotFound,
+    ParentNotFound,
+    Cycle,
+}
+
+
+
+pub enum TagMatch {
+    Any,
+    All,
+}
+
 
 
 pub enum SortBy {
@@ -134,145 +477,1598 @@ pub enum SortBy {
     Title,
     Id,
     Status,
+    Due,
+    Created,
+    Updated,
     None,
 }
 
+pub struct ParseSortByError;
+
+impl FromStr for SortBy {
+    type Err=ParseSortByError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "priority" => Ok(Self::Priority),
+            "title" => Ok(Self::Title),
+            "id" => Ok(Self::Id),
+            "status" => Ok(Self::Status),
+            "due" => Ok(Self::Due),
+            "created" => Ok(Self::Created),
+            "updated" => Ok(Self::Updated),
+            "none" => Ok(Self::None),
+            _ => Err(ParseSortByError),
+        }
+    }
+}
+
+
+
+// ..:: Autosave ::..
+
+/// When the TUI saves on its own.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Autosave {
+    #[default]
+    EveryCommand,
+    // at most once per this many seconds
+    Interval(u64),
+    Off,
+}
+
+impl Display for Autosave {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Autosave::EveryCommand => write!(f, "command"),
+            Autosave::Interval(secs) => write!(f, "{}s", secs),
+            Autosave::Off => write!(f, "off"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseAutosaveError;
+
+/// Accepts 'command', 'off' or an interval like '30s' or '5m'.
+impl FromStr for Autosave {
+    type Err = ParseAutosaveError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "command" => { return Ok(Autosave::EveryCommand); },
+            "off" => { return Ok(Autosave::Off); },
+            _ => {},
+        };
+        let (n, unit) = s.split_at(s.len().saturating_sub(1));
+        let n: u64 = n.parse().ok().ok_or(ParseAutosaveError)?;
+        match unit {
+            "s" if n > 0 => Ok(Autosave::Interval(n)),
+            "m" if n > 0 => Ok(Autosave::Interval(n * 60)),
+            _ => Err(ParseAutosaveError),
+        }
+    }
+}
+
+
+
+// ..:: Save File ::..
+
+pub const DEFAULT_PROJECT: &str = "default";
+
 
 
 // ..:: TaskManager ::..
 
-pub struct TaskManager<'a> {
+// removes the tasks with the given ids from the list and returns them,
+// their subtasks are kept and moved up to the removed task's parent
+fn take_tasks(tasks: &mut Vec<Task>, ids: &[u32]) -> Vec<Task> {
+    let mut taken = Vec::<Task>::new();
+    let mut i = 0;
+    while i < tasks.len() {
+        if ids.contains(&tasks[i].id) {
+            taken.push(tasks.remove(i));
+        } else { i += 1; }
+    }
+    let removed: HashMap<u32, Option<u32>> = taken.iter().map(|t| (t.id, t.parent)).collect();
+    for t in tasks.iter_mut() {
+        while let Some(parent) = t.parent.and_then(|p| removed.get(&p)) {
+            t.parent = *parent;
+        }
+    }
+    taken
+}
+
+/// A cycle in a graph of task ids (e.g. each task and its parent), every
+/// id in it has an edge to the next one and the last one to the first.
+pub fn find_cycle(edges: &HashMap<u32, Vec<u32>>) -> Option<Vec<u32>> {
+    // ids all of whose paths were followed without running into a cycle
+    let mut done = HashSet::<u32>::new();
+    let mut starts: Vec<u32> = edges.keys().copied().collect();
+    starts.sort();
+    for start in starts {
+        if done.contains(&start) { continue; }
+        // the path being followed, with the next edge to try from each id
+        let mut path = vec![(start, 0)];
+        while let Some(&(id, next)) = path.last() {
+            match edges.get(&id).and_then(|e| e.get(next)) {
+                Some(&to) => {
+                    if let Some(last) = path.last_mut() { last.1 += 1; }
+                    if let Some(i) = path.iter().position(|(p, _)| *p == to) {
+                        return Some(path[i..].iter().map(|(p, _)| *p).collect());
+                    }
+                    if !done.contains(&to) { path.push((to, 0)); }
+                },
+                None => {
+                    done.insert(id);
+                    path.pop();
+                },
+            };
+        }
+    }
+    None
+}
+
+pub struct TaskManager {
+    // the tasks of the active project, every task operation is scoped to them
     tasks: Vec<Task>, 
-    save_file: Box<&'a Path>,
-}
-
-impl<'a> TaskManager<'a> {
-    pub fn new(save_file: Box<&'a Path>) -> Result<TaskManager, String> {
-        let mut f = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .create(true)
-            .open(*save_file)
-            .ok()
-            .ok_or(format!("could not open file '{}'...", save_file
-                           .to_str().unwrap_or("")))?;
-        let mut buffer = String::new();
-        f.read_to_string(&mut buffer)
-            .ok().ok_or(format!("could not read file to buffer..."))?;
-        drop(f);
-        Ok(TaskManager {
-            tasks: serde_json::from_str(&buffer)
-                .ok().ok_or(format!("couldn't deserialize file content..."))?,
-            save_file,
-        })
-    }
-
-    pub fn default(save_file: Box<&'a Path>) -> TaskManager {
-        TaskManager { tasks: Vec::new(), save_file }
+    project: String,
+    // all the projects, the active one's tasks are moved out into `tasks`
+    projects: Vec<Project>,
+    next_id: u32,
+    workflow: Workflow,
+    priority_scale: PriorityScale,
+    // None if the archive file couldn't be loaded, so it never gets overwritten
+    archive: Option<Archive>,
+    // tasks done for longer than this get archived when the file is loaded
+    auto_archive_days: Option<u32>,
+    warnings: Vec<String>,
+    storage: Box<dyn Storage>,
+    // whether there are changes that haven't been saved yet
+    dirty: bool,
+    // set when loading failed, the stored data is only replaced by an
+    // explicit save then
+    load_failed: bool,
+    autosave: Autosave,
+    // task changes that can be undone, only while this runs
+    history: History,
+}
+
+impl TaskManager {
+    /// An empty task manager, `load` reads what's in the storage.
+    pub fn new(storage: Box<dyn Storage>) -> TaskManager {
+        TaskManager {
+            tasks: Vec::new(),
+            project: DEFAULT_PROJECT.to_owned(),
+            projects: vec![Project { name: DEFAULT_PROJECT.to_owned(), tasks: Vec::new() }],
+            next_id: 0,
+            workflow: Workflow::default(),
+            priority_scale: PriorityScale::default(),
+            archive: Some(Archive::default()),
+            auto_archive_days: None,
+            warnings: Vec::new(),
+            storage,
+            dirty: false,
+            load_failed: false,
+            autosave: Autosave::default(),
+            history: History::new(OPERATIONS_KEPT),
+        }
     }
 
-    pub fn save(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let serialized_tasks = serde_json::to_string(&self.tasks)?;
+    /// The file the tasks are stored in, if any.
+    pub fn path(&self) -> Option<&std::path::Path> { self.storage.path() }
+
+    // how the storage is referred to in messages
+    fn location(&self) -> String {
+        match self.storage.path() {
+            Some(path) => format!("file '{}'", path.display()),
+            None => "stored data".to_string(),
+        }
+    }
 
-        let mut f = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(*self.save_file)?;
+    /// Replaces the current state with the stored one, dropping any unsaved
+    /// changes. Nothing changes if the stored data can't be loaded.
+    pub fn load(&mut self) -> Result<(), String> {
+        let res = self.storage.load().and_then(|(data, warnings)| self.apply(data, warnings));
+        self.load_failed = res.is_err();
+        res
+    }
+
+    /// Whether the last load failed, saving would replace what couldn't be
+    /// loaded.
+    pub fn load_failed(&self) -> bool { self.load_failed }
+
+    // replaces the current state with the given data
+    fn apply(&mut self, data: SaveData, warnings: Vec<String>) -> Result<(), String> {
+        data.workflow.validate()
+            .ok().ok_or(format!("invalid workflow in {}...", self.location()))?;
+        data.priority_scale.validate()
+            .ok().ok_or(format!("invalid priority scale in {}...", self.location()))?;
+        let mut projects = data.projects;
+        if projects.is_empty() {
+            projects.push(Project { name: DEFAULT_PROJECT.to_owned(), tasks: Vec::new() });
+        }
 
-        f.write_all(serialized_tasks.as_bytes())?;
+        // ids are unique across the whole file, so the repairs run on all
+        // the projects' tasks at once
+        let mut owners = Vec::<usize>::new();
+        let mut tasks = Vec::<Task>::new();
+        for (i, p) in projects.iter_mut().enumerate() {
+            owners.extend(std::iter::repeat_n(i, p.tasks.len()));
+            tasks.append(&mut p.tasks);
+        }
+        self.tasks = tasks;
+        self.project = String::new();
+        self.projects = projects;
+        self.next_id = data.next_id;
+        self.workflow = data.workflow;
+        self.priority_scale = data.priority_scale;
+        self.auto_archive_days = data.auto_archive_days;
+        self.autosave = data.autosave;
+        self.history.clear();
+        // upgrades and repairs are changes that still need to be written
+        self.dirty = !warnings.is_empty();
+        self.warnings.extend(warnings);
 
-        drop(f);
+        // without a file the archive only lives as long as the task manager
+        if let Some(path) = self.storage.path() {
+            match Archive::load(&Archive::path_for(path), self.storage.cipher()) {
+                Ok(archive) => { self.archive = Some(archive); },
+                Err(e) => {
+                    self.archive = None;
+                    self.warnings.push(e);
+                },
+            };
+        }
+        let repairs = self.warnings.len();
+        self.repair_priorities();
+        self.repair_ids();
+        self.repair_cycles();
+        self.dirty |= self.warnings.len() > repairs;
+        for (t, owner) in std::mem::take(&mut self.tasks).into_iter().zip(owners) {
+            self.projects[owner].tasks.push(t);
+        }
 
+        let active = data.active_project
+            .filter(|a| self.projects.iter().any(|p| p.name == *a))
+            .unwrap_or(self.projects[0].name.clone());
+        // opening the project that was active isn't a change
+        let dirty = self.dirty;
+        self.switch_project(&active);
+        self.dirty = dirty;
+        if let Ok(n) = self.auto_archive() {
+            if n > 0 {
+                self.warnings.push(format!("auto-archived {} tasks done for more than {} days...",
+                    n, self.auto_archive_days.unwrap_or(0)));
+            }
+        }
         Ok(())
     }
 
-    pub fn new_task(&mut self, title: &str, description: &str, priority: Priority, status: Status) {
-        self.tasks.push(
-            Task::new(self.tasks.len() as u32, title, description, priority, status)
-        );
+    /// The current state, as it would be saved.
+    fn snapshot(&self) -> SaveData {
+        SaveData {
+            next_id: self.next_id,
+            workflow: self.workflow.clone(),
+            priority_scale: self.priority_scale.clone(),
+            auto_archive_days: self.auto_archive_days,
+            autosave: self.autosave,
+            active_project: Some(self.project.clone()),
+            projects: self.projects
+                .iter()
+                .map(|p| Project {
+                    name: p.name.clone(),
+                    tasks: if p.name == self.project { self.tasks.clone() } else { p.tasks.clone() },
+                })
+                .collect(),
+        }
     }
 
-    pub fn remove_task(&mut self, task_selector: TaskSelector) {
-        match task_selector {
-            TaskSelector::Title(title) => {
-                let mut i = 0;
-                while i < self.tasks.len() {
-                    if self.tasks[i].title == title {
-                        self.tasks.remove(i);
-                    } else { i += 1; }
-                }
-            },
-            TaskSelector::Id(id) => {
-                let mut i = 0;
-                while i < self.tasks.len() {
-                    if self.tasks[i].id == id {
-                        self.tasks.remove(i);
-                    } else { i += 1; }
-                }
-            },
-        };
+    /// Whether the stored data changed (e.g. another program edited the
+    /// file) since it was loaded or saved. Saving would overwrite those
+    /// changes, so `save` refuses to.
+    pub fn has_external_changes(&self) -> bool { self.storage.changed_externally() }
+
+    pub fn save(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.has_external_changes() {
+            return Err(format!("{} changed since it was loaded, reload or merge first...", self.location()).into());
+        }
+        self.overwrite()
     }
 
-    pub fn get_task_by_title(&mut self, title: &str) -> Option<&mut Task> {
-        for t in self.tasks.iter_mut() {
-            if t.title == title {
-                return Some(t);
-            }
+    /// Merges the external changes to the stored data into the current
+    /// state, task by task. Tasks changed on both sides keep the local
+    /// version and are returned as conflicts, along with local tasks that
+    /// had to take a new id.
+    pub fn merge_external(&mut self) -> Result<MergeReport, String> {
+        let base = self.storage
+            .base()
+            .ok_or(format!("can't merge, the last loaded version of the {} isn't known...", self.location()))?;
+        let ours = self.snapshot();
+        let (theirs, warnings) = self.storage.load()?;
+        let merged = merge::merge(&base, &ours, &theirs);
+        self.apply(merged.data, warnings)?;
+        self.dirty = true;
+        Ok(merged.report)
+    }
+
+    /// Saves even if that overwrites external changes.
+    pub fn overwrite(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        // the archive goes first: if writing the save file fails afterwards,
+        // archived tasks end up in both files instead of in neither
+        if let (Some(archive), Some(path)) = (&mut self.archive, self.storage.path()) {
+            archive.save(&Archive::path_for(path), self.storage.cipher())?;
         }
-        return None
+
+        self.storage.save(&SaveDataRef {
+            version: schema::CURRENT_VERSION,
+            next_id: self.next_id,
+            workflow: &self.workflow,
+            priority_scale: &self.priority_scale,
+            auto_archive_days: self.auto_archive_days,
+            autosave: self.autosave,
+            active_project: &self.project,
+            projects: self.projects
+                .iter()
+                .map(|p| ProjectRef {
+                    name: &p.name,
+                    tasks: if p.name == self.project { &self.tasks } else { &p.tasks },
+                })
+                .collect(),
+        })?;
+        self.dirty = false;
+        self.load_failed = false;
+        Ok(())
     }
-    pub fn get_task_by_id(&mut self, id: u32) -> Option<&mut Task> {
-        for t in self.tasks.iter_mut() {
-            if t.id == id {
-                return Some(t);
+
+    /// Whether there are changes that haven't been saved yet.
+    pub fn is_dirty(&self) -> bool { self.dirty }
+
+    pub fn autosave(&self) -> Autosave { self.autosave }
+
+    pub fn set_autosave(&mut self, autosave: Autosave) {
+        self.autosave = autosave;
+        self.dirty = true;
+    }
+
+    // writes the tasks (or their removal, for those that are gone) straight
+    // to storages that support it, failures are left for the next save
+    fn write_through(&mut self, ids: &[u32]) {
+        // what couldn't be loaded is only replaced by an explicit save
+        if self.load_failed { return; }
+        for id in ids {
+            let change = match self.tasks.iter().find(|t| t.id == *id) {
+                Some(task) => Change::TaskSaved { project: &self.project, task },
+                None => match self.projects
+                    .iter()
+                    .find_map(|p| p.tasks.iter().find(|t| t.id == *id).map(|t| (p, t)))
+                {
+                    Some((p, task)) => Change::TaskSaved { project: &p.name, task },
+                    None => Change::TaskRemoved(*id),
+                },
+            };
+            if let Err(e) = self.storage.record(change) {
+                self.warnings.push(format!("could not write task '{}', it will be on the next save: {}...", id, e));
             }
         }
-        return None
     }
 
-    pub fn change_task_status(&mut self, task_selector: TaskSelector, new_status: Status) -> Result<(), TaskNotFountError> {
-        let task: Option<&mut Task> = match task_selector {
-            TaskSelector::Title(title) => self.get_task_by_title(title),
-            TaskSelector::Id(id) => self.get_task_by_id(id),
-        };
-        if let Some(t) = task {
-            t.status = new_status;
-        } else {
-            return Err(TaskNotFountError);
+    pub fn backups(&self) -> Vec<Backup> { self.storage.backups() }
+
+    /// Saves, syncs the task file with a git remote (see `sync::sync`) and
+    /// loads the result.
+    pub fn sync(&mut self, remote: Option<&str>) -> Result<SyncReport, String> {
+        let path = self.storage
+            .path()
+            .ok_or("can't sync, the tasks aren't stored in a file...".to_string())?
+            .to_owned();
+        // checked before saving, which would otherwise write the file anyway
+        if self.storage.cipher().is_some() {
+            return Err(format!("can't sync '{}', encrypted task files can't be synced...", path.display()));
         }
-        Ok(())
+        if self.dirty || self.has_external_changes() {
+            self.save().map_err(|e| format!("{}", e))?;
+        }
+        let report = sync::sync(&path, remote)?;
+        if report.pulled {
+            self.load()?;
+        }
+        Ok(report)
     }
 
-    pub fn change_task_priority(&mut self, task_selector: TaskSelector, new_priority: Priority) -> Result<(), TaskNotFountError> {
-        let task: Option<&mut Task> = match task_selector {
-            TaskSelector::Title(title) => self.get_task_by_title(title),
-            TaskSelector::Id(id) => self.get_task_by_id(id),
-        };
-        if let Some(t) = task {
-            t.priority = new_priority;
-        } else {
-            return Err(TaskNotFountError);
+    /// Encrypts the stored data with a new passphrase, or stops encrypting
+    /// it, and saves right away. The archive follows the task file.
+    pub fn set_passphrase(&mut self, passphrase: Option<&str>) -> Result<(), String> {
+        if self.has_external_changes() {
+            return Err(format!("{} changed since it was loaded, reload or merge first...", self.location()));
         }
-        Ok(())
+        self.storage.set_passphrase(passphrase)?;
+        if let Some(archive) = &mut self.archive {
+            archive.mark_changed();
+        }
+        self.overwrite().map_err(|e| format!("could not save with the new passphrase: {}...", e))
     }
 
-    pub fn filter_task_status(&self, status: Status) -> Vec<&Task> {
-        self.tasks
+    /// Every stored version of a task, oldest first. Unsaved changes
+    /// aren't part of it.
+    pub fn task_history(&self, id: u32) -> Result<Vec<TaskVersion>, String> {
+        self.storage.task_history(id)
+    }
+
+    /// Starts recording an operation on the active project's tasks, which
+    /// `end_operation` finishes. `label` describes it when it's undone.
+    pub fn begin_operation(&mut self, label: &str) {
+        self.history.begin(label, &self.project, &self.tasks);
+    }
+
+    /// Finishes the operation begun last, operations that changed nothing
+    /// aren't recorded.
+    pub fn end_operation(&mut self) {
+        self.history.end(&self.project, &self.tasks);
+    }
+
+    /// Reverts the last recorded operation, returns its label.
+    pub fn undo(&mut self) -> Result<String, HistoryError> {
+        let op = self.history.last_undo().ok_or(HistoryError::Empty)?;
+        if op.project != self.project {
+            return Err(HistoryError::OtherProject(op.project.clone()));
+        }
+        let op = self.history.undo().ok_or(HistoryError::Empty)?;
+        self.replace_tasks(op.before);
+        Ok(op.label)
+    }
+
+    /// Does the last undone operation again, returns its label.
+    pub fn redo(&mut self) -> Result<String, HistoryError> {
+        let op = self.history.last_redo().ok_or(HistoryError::Empty)?;
+        if op.project != self.project {
+            return Err(HistoryError::OtherProject(op.project.clone()));
+        }
+        let op = self.history.redo().ok_or(HistoryError::Empty)?;
+        self.replace_tasks(op.after);
+        Ok(op.label)
+    }
+
+    // replaces the active project's tasks, the ones that differ are
+    // written through
+    fn replace_tasks(&mut self, tasks: Vec<Task>) {
+        let old = std::mem::replace(&mut self.tasks, tasks);
+        let mut changed: Vec<u32> = self.tasks
+            .iter()
+            .filter(|t| old.iter().find(|o| o.id == t.id).map(|o| !storage::same(o, t)).unwrap_or(true))
+            .map(|t| t.id)
+            .collect();
+        changed.extend(old.iter().filter(|o| !self.tasks.iter().any(|t| t.id == o.id)).map(|o| o.id));
+        self.update_derived_state();
+        self.write_through(&changed);
+        self.dirty = true;
+    }
+
+    /// Puts an earlier version of the data back in the storage and loads it.
+    pub fn restore_backup(&mut self, backup: &Backup) -> Result<(), String> {
+        self.storage.restore_backup(backup)?;
+        self.load()
+    }
+
+    pub fn new_task(&mut self, title: &str, description: &str, priority: Priority, status: Status) -> u32 {
+        let id = self.allocate_id();
+        let hash = self.generate_hash(id, title);
+        self.tasks.push(
+            Task::new(id, &hash, title, description, priority, status)
+        );
+        self.update_derived_state();
+        self.write_through(&[id]);
+        self.dirty = true;
+        id
+    }
+
+    /// Hands out the next id from the persisted counter. Ids are never
+    /// reused, even after the task that owned them is removed.
+    fn allocate_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    /// Generates a short hex id (e.g. 'a3f9') that is unique among the
+    /// current tasks. All-digit hashes are skipped so they can never be
+    /// mistaken for a numeric id.
+    fn generate_hash(&self, id: u32, title: &str) -> String {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        let mut salt: u32 = 0;
+        loop {
+            let mut hasher = DefaultHasher::new();
+            (id, title, nanos, salt).hash(&mut hasher);
+            // widen the hash if the short space is getting crowded
+            let hash = if salt < 64 {
+                format!("{:04x}", hasher.finish() & 0xffff)
+            } else {
+                format!("{:06x}", hasher.finish() & 0xffffff)
+            };
+            if hash.parse::<u32>().is_err() && !self.all_tasks().any(|t| t.hash == hash) {
+                return hash;
+            }
+            salt += 1;
+        }
+    }
+
+    // the tasks of every project
+    fn all_tasks(&self) -> impl Iterator<Item = &Task> {
+        self.tasks.iter().chain(self.projects.iter().flat_map(|p| p.tasks.iter()))
+    }
+
+    fn all_tasks_mut(&mut self) -> impl Iterator<Item = &mut Task> {
+        self.tasks.iter_mut().chain(self.projects.iter_mut().flat_map(|p| p.tasks.iter_mut()))
+    }
+
+    pub fn project(&self) -> &str { &self.project }
+
+    /// Names of all the projects, with their number of tasks.
+    pub fn projects(&self) -> Vec<(String, usize)> {
+        self.projects
             .iter()
-            .filter(|e| e.status == status)
+            .map(|p| (p.name.clone(), if p.name == self.project { self.tasks.len() } else { p.tasks.len() }))
             .collect()
     }
 
-    pub fn log_tasks<W: Write>(&mut self, handle: &mut W, sort_by: SortBy) {
-        match sort_by {
-            SortBy::Id => self.tasks.sort_by_key(|e| e.id),
-            SortBy::Title => self.tasks.sort_by_key(|e| e.title.chars().nth(0)),
-            SortBy::Priority => self.tasks.sort_by_key(|e| e.priority),
-            SortBy::Status => self.tasks.sort_by_key(|e| e.status),
-            SortBy::None => {},
+    /// Makes the project the active one, creating it if it doesn't exist.
+    pub fn switch_project(&mut self, name: &str) {
+        if name == self.project { return; }
+        let tasks = std::mem::take(&mut self.tasks);
+        if let Some(current) = self.projects.iter_mut().find(|p| p.name == self.project) {
+            current.tasks = tasks;
+        }
+        match self.projects.iter_mut().find(|p| p.name == name) {
+            Some(target) => { self.tasks = std::mem::take(&mut target.tasks); },
+            None => { self.projects.push(Project { name: name.to_owned(), tasks: Vec::new() }); },
         };
-        for t in self.tasks.iter() {
-            write!(handle, "{}. {}", t.id, t).unwrap();
+        self.project = name.to_owned();
+        self.update_derived_state();
+        self.dirty = true;
+    }
+
+    /// Moves a task, along with its subtasks, to another existing project.
+    pub fn move_task(&mut self, id: u32, project: &str) -> Result<(), MoveError> {
+        if project == self.project {
+            return Ok(());
+        }
+        if !self.projects.iter().any(|p| p.name == project) {
+            return Err(MoveError::ProjectNotFound);
         }
+        let ids: Vec<u32> = self.subtree(id).iter().map(|(_, t)| t.id).collect();
+        if ids.is_empty() {
+            return Err(MoveError::TaskNotFound);
+        }
+        let mut moved = Vec::<Task>::new();
+        let mut i = 0;
+        while i < self.tasks.len() {
+            if ids.contains(&self.tasks[i].id) {
+                let mut t = self.tasks.remove(i);
+                // the task's parent stays behind
+                if t.id == id { t.parent = None; }
+                t.touch();
+                moved.push(t);
+            } else { i += 1; }
+        }
+        if let Some(target) = self.projects.iter_mut().find(|p| p.name == project) {
+            target.tasks.append(&mut moved);
+        }
+        // undoing would bring the moved tasks back while they're still in
+        // the other project
+        self.history.clear();
+        self.update_derived_state();
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Every task of every project, grouped by project name.
+    pub fn all_projects_tasks(&self) -> Vec<(&str, Vec<&Task>)> {
+        self.projects
+            .iter()
+            .map(|p| (p.name.as_str(), if p.name == self.project {
+                self.tasks.iter().collect()
+            } else {
+                p.tasks.iter().collect()
+            }))
+            .collect()
+    }
+
+    /// Fixes save files written by older versions, which could contain the
+    /// same id more than once and had no id counter or short hashes.
+    fn repair_ids(&mut self) {
+        if let Some(max_id) = self.tasks.iter().map(|t| t.id).max() {
+            self.next_id = self.next_id.max(max_id + 1);
+        }
+
+        let mut seen_ids = HashSet::<u32>::new();
+        let mut seen_hashes = HashSet::<String>::new();
+        for i in 0..self.tasks.len() {
+            if !seen_ids.insert(self.tasks[i].id) {
+                let new_id = self.allocate_id();
+                self.warnings.push(format!(
+                    "task '{}' had duplicate id '{}', reassigned to '{}'...",
+                    self.tasks[i].title, self.tasks[i].id, new_id));
+                self.tasks[i].id = new_id;
+                seen_ids.insert(new_id);
+            }
+            if self.tasks[i].hash.is_empty() || !seen_hashes.insert(self.tasks[i].hash.clone()) {
+                self.tasks[i].hash = String::new();
+                let hash = self.generate_hash(self.tasks[i].id, &self.tasks[i].title);
+                self.tasks[i].hash = hash.clone();
+                seen_hashes.insert(hash);
+            }
+        }
+    }
+
+    /// Breaks the cycles a hand-edited or merged file can have, tasks that
+    /// are their own ancestors or depend on themselves would have walking
+    /// the tree or the dependencies go on forever.
+    fn repair_cycles(&mut self) {
+        let parents = |tasks: &[Task]| -> HashMap<u32, Vec<u32>> {
+            tasks.iter().map(|t| (t.id, t.parent.into_iter().collect())).collect()
+        };
+        while let Some(cycle) = find_cycle(&parents(&self.tasks)) {
+            if let Some(t) = self.tasks.iter_mut().find(|t| t.id == cycle[0]) {
+                self.warnings.push(format!(
+                    "task '{}' was its own ancestor, moved it to the top level...", t.id));
+                t.parent = None;
+            }
+        }
+        let dependencies = |tasks: &[Task]| -> HashMap<u32, Vec<u32>> {
+            tasks.iter().map(|t| (t.id, t.depends_on.iter().copied().collect())).collect()
+        };
+        while let Some(cycle) = find_cycle(&dependencies(&self.tasks)) {
+            let dependency = cycle[1 % cycle.len()];
+            if let Some(t) = self.tasks.iter_mut().find(|t| t.id == cycle[0]) {
+                self.warnings.push(format!(
+                    "task '{}' depended on itself through task '{}', removed that dependency...", t.id, dependency));
+                t.depends_on.remove(&dependency);
+            }
+        }
+    }
+
+    pub fn workflow(&self) -> &Workflow { &self.workflow }
+
+    /// Replaces the task file's statuses. Fails if a task of any project
+    /// is still in a status that isn't part of the new ones.
+    pub fn set_statuses(&mut self, statuses: Vec<StatusDef>) -> Result<(), WorkflowError> {
+        let mut workflow = Workflow::new(statuses)?;
+        if let Some(t) = self.all_tasks().find(|t| !workflow.contains(&t.status)) {
+            return Err(WorkflowError::UnknownStatus(t.status.name().to_owned()));
+        }
+        workflow.carry_over_transitions(&self.workflow);
+        self.workflow = workflow;
+        // undoing could bring back tasks in statuses that are gone
+        self.history.clear();
+        self.update_derived_state();
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn priority_scale(&self) -> &PriorityScale { &self.priority_scale }
+
+    /// Replaces the task file's priority scale, moving every task of every
+    /// project to the level at the same relative position on the new scale.
+    pub fn set_priority_scale(&mut self, scale: PriorityScale) -> Result<(), ScaleError> {
+        scale.validate()?;
+        let old = std::mem::replace(&mut self.priority_scale, scale);
+        let scale = self.priority_scale.clone();
+        for t in self.all_tasks_mut() {
+            t.priority = scale.rescale(t.priority, &old);
+        }
+        self.history.clear();
+        self.update_derived_state();
+        self.dirty = true;
+        Ok(())
+    }
+
+    // levels above the top of the scale (e.g. from a hand-edited file) are
+    // clamped to the highest one
+    fn repair_priorities(&mut self) {
+        let highest = self.priority_scale.highest();
+        for t in self.tasks.iter_mut() {
+            if t.priority > highest {
+                self.warnings.push(format!(
+                    "task '{}' had priority '{}' outside of the scale, set to '{}'...",
+                    t.id, t.priority, self.priority_scale.name(highest)));
+                t.priority = highest;
+            }
+        }
+    }
+
+    pub fn set_transitions(&mut self, from: &Status, to: Option<Vec<Status>>) -> Result<(), WorkflowError> {
+        self.workflow.set_transitions(from, to)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Returns (and clears) the problems found and repaired while loading.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Removes the matching tasks. Their subtasks are kept and moved up to
+    /// the removed task's parent.
+    pub fn remove_task(&mut self, task_selector: TaskSelector) {
+        let ids: Vec<u32> = match task_selector {
+            TaskSelector::Title(title) => self.tasks.iter().filter(|t| t.title == title).map(|t| t.id).collect(),
+            TaskSelector::Id(id) => vec![id],
+        };
+        // the subtasks get a new parent
+        let mut changed: Vec<u32> = self.tasks
+            .iter()
+            .filter(|t| t.parent.map(|p| ids.contains(&p)).unwrap_or(false))
+            .map(|t| t.id)
+            .collect();
+        changed.extend(take_tasks(&mut self.tasks, &ids).iter().map(|t| t.id));
+        self.update_derived_state();
+        self.write_through(&changed);
+        self.dirty = true;
+    }
+
+    /// Moves a task of the active project to the archive.
+    pub fn archive_task(&mut self, id: u32) -> Result<(), ArchiveError> {
+        let archive = self.archive.as_mut().ok_or(ArchiveError::Unavailable)?;
+        let taken = take_tasks(&mut self.tasks, &[id]);
+        if taken.is_empty() {
+            return Err(ArchiveError::TaskNotFound);
+        }
+        for mut t in taken {
+            t.stop_timer();
+            archive.push(&self.project, t);
+        }
+        self.history.clear();
+        self.update_derived_state();
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Archives every done task of the active project, returns their ids.
+    pub fn archive_done(&mut self) -> Result<Vec<u32>, ArchiveError> {
+        let done = self.workflow.done();
+        let ids: Vec<u32> = self.tasks
+            .iter()
+            .filter(|t| t.status == done)
+            .map(|t| t.id)
+            .collect();
+        for id in ids.iter() {
+            self.archive_task(*id)?;
+        }
+        Ok(ids)
+    }
+
+    /// Sets (or disables) auto-archiving and applies it right away,
+    /// returns the number of archived tasks.
+    pub fn set_auto_archive(&mut self, days: Option<u32>) -> Result<usize, ArchiveError> {
+        self.auto_archive_days = days;
+        self.dirty = true;
+        self.auto_archive()
+    }
+
+    // archives the tasks of every project that have been done for longer
+    // than the auto-archive setting
+    fn auto_archive(&mut self) -> Result<usize, ArchiveError> {
+        let days = match self.auto_archive_days {
+            Some(days) => days,
+            None => { return Ok(0); },
+        };
+        let archive = self.archive.as_mut().ok_or(ArchiveError::Unavailable)?;
+        let cutoff = Local::now() - Duration::days(days as i64);
+        let done = self.workflow.done();
+        let expired = |tasks: &Vec<Task>| -> Vec<u32> {
+            tasks
+                .iter()
+                .filter(|t| t.status == done && t.completed_at.map(|c| c < cutoff).unwrap_or(false))
+                .map(|t| t.id)
+                .collect()
+        };
+
+        let mut count = 0;
+        let ids = expired(&self.tasks);
+        for t in take_tasks(&mut self.tasks, &ids) {
+            archive.push(&self.project, t);
+            count += 1;
+        }
+        for p in self.projects.iter_mut() {
+            let ids = expired(&p.tasks);
+            for t in take_tasks(&mut p.tasks, &ids) {
+                archive.push(&p.name, t);
+                count += 1;
+            }
+        }
+        if count > 0 { self.history.clear(); }
+        self.update_derived_state();
+        self.dirty |= count > 0;
+        Ok(count)
+    }
+
+    pub fn search_archive(&self, query: &str) -> Result<Vec<&ArchivedTask>, ArchiveError> {
+        Ok(self.archive.as_ref().ok_or(ArchiveError::Unavailable)?.search(query))
+    }
+
+    pub fn archived_task(&self, id: u32) -> Result<&ArchivedTask, ArchiveError> {
+        self.archive
+            .as_ref()
+            .ok_or(ArchiveError::Unavailable)?
+            .get(id)
+            .ok_or(ArchiveError::TaskNotFound)
+    }
+
+    /// Moves an archived task back into the project it was archived from
+    /// (recreating the project if needed), returns the project's name.
+    pub fn restore_task(&mut self, id: u32) -> Result<String, ArchiveError> {
+        let archived = self.archive
+            .as_mut()
+            .ok_or(ArchiveError::Unavailable)?
+            .take(id)
+            .ok_or(ArchiveError::TaskNotFound)?;
+        let mut task = archived.task;
+        task.touch();
+        // the parent may be gone by now
+        if let Some(parent) = task.parent {
+            if !self.all_tasks().any(|t| t.id == parent) {
+                task.parent = None;
+            }
+        }
+        if archived.project == self.project {
+            self.tasks.push(task);
+        } else {
+            match self.projects.iter_mut().find(|p| p.name == archived.project) {
+                Some(p) => { p.tasks.push(task); },
+                None => {
+                    self.projects.push(Project { name: archived.project.clone(), tasks: vec![task] });
+                },
+            };
+        }
+        self.history.clear();
+        self.update_derived_state();
+        self.dirty = true;
+        Ok(archived.project)
+    }
+
+    /// Tasks of the active project with conflicts left by a merge.
+    pub fn conflicted_tasks(&self) -> Vec<&Task> {
+        self.tasks.iter().filter(|t| !t.conflicts.is_empty()).collect()
+    }
+
+    /// Settles a task's merge conflicts (or only the one on `field`) by
+    /// taking one side's values. Taking the side that removed the task
+    /// removes it.
+    pub fn resolve_conflict(&mut self, id: u32, side: Side, field: Option<&str>) -> Result<(), ConflictError> {
+        let task = self.tasks
+            .iter()
+            .find(|t| t.id == id)
+            .ok_or(ConflictError::TaskNotFound)?;
+        let (chosen, kept): (Vec<FieldConflict>, Vec<FieldConflict>) = task.conflicts
+            .iter()
+            .cloned()
+            .partition(|c| field.map(|f| c.field == f).unwrap_or(true));
+        if chosen.is_empty() {
+            return Err(ConflictError::NoConflict);
+        }
+
+        // the task holds our values already
+        let mut remove = false;
+        let mut value = serde_json::to_value(task).unwrap_or_default();
+        value["conflicts"] = serde_json::to_value(&kept).unwrap_or_default();
+        for c in chosen {
+            let chosen_value = if side == Side::Ours { c.ours } else { c.theirs };
+            if c.field == "task" {
+                remove = chosen_value == merge::REMOVED;
+            } else if side == Side::Theirs {
+                value[&c.field] = chosen_value;
+            }
+        }
+        let resolved = serde_json::from_value::<Task>(value).ok();
+        if let Some(r) = &resolved {
+            // their value was set aside because it closes a cycle with ours
+            if r.parent.is_some_and(|p| self.subtree(id).iter().any(|(_, t)| t.id == p))
+                || r.depends_on.iter().any(|d| self.depends_transitively(*d, id))
+            {
+                return Err(ConflictError::Cycle);
+            }
+        }
+        if let Some(task) = self.get_task_by_id(id) {
+            match resolved {
+                Some(mut resolved) => {
+                    resolved.touch();
+                    *task = resolved;
+                },
+                None => { task.conflicts = kept; },
+            };
+        }
+        if remove {
+            self.remove_task(TaskSelector::Id(id));
+            return Ok(());
+        }
+        self.update_derived_state();
+        self.write_through(&[id]);
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Removes a task together with all of its subtasks.
+    pub fn remove_task_and_children(&mut self, id: u32) {
+        let ids: Vec<u32> = self.subtree(id).iter().map(|(_, t)| t.id).collect();
+        self.tasks.retain(|t| !ids.contains(&t.id));
+        self.update_derived_state();
+        self.write_through(&ids);
+        self.dirty = true;
+    }
+
+    pub fn children(&self, id: u32) -> Vec<&Task> {
+        self.tasks
+            .iter()
+            .filter(|t| t.parent == Some(id))
+            .collect()
+    }
+
+    /// The task followed by all of its descendants in depth-first order,
+    /// each paired with its depth below the task.
+    pub fn subtree(&self, id: u32) -> Vec<(usize, &Task)> {
+        let mut res = Vec::<(usize, &Task)>::new();
+        let mut stack: Vec<(usize, &Task)> = match self.tasks.iter().find(|t| t.id == id) {
+            Some(t) => vec![(0, t)],
+            None => Vec::new(),
+        };
+        while let Some((depth, t)) = stack.pop() {
+            res.push((depth, t));
+            for c in self.children(t.id).into_iter().rev() {
+                stack.push((depth + 1, c));
+            }
+        }
+        res
+    }
+
+    pub fn set_task_parent(&mut self, id: u32, parent: Option<u32>) -> Result<(), ParentError> {
+        if !self.tasks.iter().any(|t| t.id == id) {
+            return Err(ParentError::TaskNotFound);
+        }
+        if let Some(p) = parent {
+            if !self.tasks.iter().any(|t| t.id == p) {
+                return Err(ParentError::ParentNotFound);
+            }
+            if self.subtree(id).iter().any(|(_, t)| t.id == p) {
+                return Err(ParentError::Cycle);
+            }
+        }
+        if let Some(t) = self.get_task_by_id(id) {
+            t.parent = parent;
+            t.touch();
+        }
+        self.update_derived_state();
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// If finishing `id` left its parent with every subtask done (and the
+    /// parent itself isn't done yet), returns the parent's id.
+    pub fn completable_parent(&self, id: u32) -> Option<u32> {
+        let parent = self.tasks.iter().find(|t| t.id == id)?.parent?;
+        let parent_task = self.tasks.iter().find(|t| t.id == parent)?;
+        let done = self.workflow.done();
+        if parent_task.status != done
+            && self.children(parent).iter().all(|c| c.status == done)
+        {
+            return Some(parent);
+        }
+        None
+    }
+
+    pub fn add_dependency(&mut self, id: u32, dependency: u32) -> Result<(), DependencyError> {
+        if !self.tasks.iter().any(|t| t.id == id) {
+            return Err(DependencyError::TaskNotFound);
+        }
+        if !self.tasks.iter().any(|t| t.id == dependency) {
+            return Err(DependencyError::DependencyNotFound);
+        }
+        if self.depends_transitively(dependency, id) {
+            return Err(DependencyError::Cycle);
+        }
+        if let Some(t) = self.get_task_by_id(id) {
+            t.depends_on.insert(dependency);
+            t.touch();
+        }
+        self.update_derived_state();
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn remove_dependency(&mut self, id: u32, dependency: u32) -> Result<(), DependencyError> {
+        match self.get_task_by_id(id) {
+            Some(t) => {
+                t.depends_on.remove(&dependency);
+                t.touch();
+            },
+            None => { return Err(DependencyError::TaskNotFound); },
+        };
+        self.update_derived_state();
+        self.dirty = true;
+        Ok(())
+    }
+
+    // whether `from` depends on `to`, directly or through other tasks
+    // (a task counts as depending on itself)
+    fn depends_transitively(&self, from: u32, to: u32) -> bool {
+        let mut visited = HashSet::<u32>::new();
+        let mut stack = vec![from];
+        while let Some(id) = stack.pop() {
+            if id == to { return true; }
+            if !visited.insert(id) { continue; }
+            if let Some(t) = self.all_tasks().find(|t| t.id == id) {
+                stack.extend(t.depends_on.iter());
+            }
+        }
+        false
+    }
+
+    /// Tasks that haven't been started yet (i.e. in a status before the
+    /// workflow's active one) whose dependencies are all done, most
+    /// important first.
+    pub fn next_tasks(&self) -> Vec<&Task> {
+        let active = self.workflow.index(&self.workflow.active());
+        let mut res: Vec<&Task> = self.tasks
+            .iter()
+            .filter(|t| self.workflow.index(&t.status) < active && !t.blocked)
+            .collect();
+        res.sort_by_key(|t| (std::cmp::Reverse(t.priority), t.due.is_none(), t.due, t.id));
+        res
+    }
+
+    // recomputes the fields of each task that are derived from other tasks
+    fn update_derived_state(&mut self) {
+        // dependencies on tasks that no longer exist don't block anything
+        let done = self.workflow.done();
+        let unfinished: HashSet<u32> = self
+            .all_tasks()
+            .filter(|t| t.status != done)
+            .map(|t| t.id)
+            .collect();
+        for t in self.all_tasks_mut() {
+            t.blocked = t.depends_on.iter().any(|d| unfinished.contains(d));
+        }
+
+        let levels = self.priority_scale.levels();
+        for t in self.all_tasks_mut() {
+            t.scale_levels = levels;
+        }
+
+        let mut progress = HashMap::<u32, (usize, usize)>::new();
+        for t in self.all_tasks() {
+            if let Some(p) = t.parent {
+                let entry = progress.entry(p).or_insert((0, 0));
+                if t.status == done { entry.0 += 1; }
+                entry.1 += 1;
+            }
+        }
+        for t in self.all_tasks_mut() {
+            t.progress = progress.get(&t.id).copied();
+        }
+    }
+
+    pub fn get_task_by_title(&mut self, title: &str) -> Option<&mut Task> {
+        self.tasks.iter_mut().find(|t| t.title == title)
+    }
+    pub fn get_task_by_hash(&self, hash: &str) -> Option<&Task> {
+        let hash = hash.trim_start_matches('#');
+        self.tasks.iter().find(|t| t.hash == hash)
+    }
+    pub fn get_task_by_id(&mut self, id: u32) -> Option<&mut Task> {
+        self.tasks.iter_mut().find(|t| t.id == id)
+    }
+
+    pub fn change_task_status(&mut self, task_selector: TaskSelector, new_status: Status) -> Result<(), StatusError> {
+        if !self.workflow.contains(&new_status) {
+            return Err(StatusError::UnknownStatus);
+        }
+        let done = self.workflow.done();
+        let current = match &task_selector {
+            TaskSelector::Title(title) => self.tasks.iter().find(|t| t.title == *title),
+            TaskSelector::Id(id) => self.tasks.iter().find(|t| t.id == *id),
+        }.map(|t| t.status.clone());
+        if let Some(current) = current {
+            if !self.workflow.allows(&current, &new_status) {
+                return Err(StatusError::NotAllowed(current, new_status));
+            }
+        }
+
+        let task: Option<&mut Task> = match task_selector {
+            TaskSelector::Title(title) => self.get_task_by_title(title),
+            TaskSelector::Id(id) => self.get_task_by_id(id),
+        };
+        let mut completed = None;
+        let mut changed = Vec::<u32>::new();
+        if let Some(t) = task {
+            changed.push(t.id);
+            t.touch();
+            if new_status == done && t.status != done {
+                t.completed_at = Some(Local::now());
+                t.stop_timer();
+                completed = Some(t.id);
+            } else if new_status != done {
+                t.completed_at = None;
+            }
+            if t.status != new_status {
+                t.record(Activity::StatusChanged(t.status.clone(), new_status.clone()));
+            }
+            t.status = new_status;
+        } else {
+            return Err(StatusError::TaskNotFound);
+        }
+        if let Some(id) = completed {
+            changed.extend(self.spawn_next_occurrence(id));
+        }
+        self.update_derived_state();
+        self.write_through(&changed);
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn change_task_priority(&mut self, task_selector: TaskSelector, new_priority: Priority) -> Result<(), TaskNotFountError> {
+        let task: Option<&mut Task> = match task_selector {
+            TaskSelector::Title(title) => self.get_task_by_title(title),
+            TaskSelector::Id(id) => self.get_task_by_id(id),
+        };
+        let id = if let Some(t) = task {
+            t.touch();
+            if t.priority != new_priority {
+                t.record(Activity::PriorityChanged(t.priority, new_priority));
+            }
+            t.priority = new_priority;
+            t.id
+        } else {
+            return Err(TaskNotFountError);
+        };
+        self.write_through(&[id]);
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Starts a timer on the task and moves it to Doing. Only one timer runs
+    /// at a time: a timer running on another task gets stopped, and that
+    /// task's id is returned.
+    pub fn start_timer(&mut self, id: u32) -> Result<Option<u32>, TaskNotFountError> {
+        if !self.tasks.iter().any(|t| t.id == id) {
+            return Err(TaskNotFountError);
+        }
+        let mut stopped = None;
+        for t in self.tasks.iter_mut() {
+            if t.stop_timer() {
+                t.touch();
+                if t.id != id { stopped = Some(t.id); }
+            }
+        }
+        // timers running in other projects get stopped too, undoing only
+        // covers the active project so it couldn't restart them
+        for t in self.projects.iter_mut().flat_map(|p| p.tasks.iter_mut()) {
+            if t.stop_timer() {
+                t.touch();
+                stopped = Some(t.id);
+                self.history.clear();
+            }
+        }
+        if let Some(t) = self.get_task_by_id(id) {
+            t.intervals.push(WorkInterval { start: Local::now(), end: None });
+        }
+        // the timer runs even if the workflow doesn't allow the move
+        let _ = self.change_task_status(TaskSelector::Id(id), self.workflow.active());
+        self.dirty = true;
+        Ok(stopped)
+    }
+
+    /// Stops the task's timer, returns whether one was running.
+    pub fn stop_timer(&mut self, id: u32) -> Result<bool, TaskNotFountError> {
+        let stopped = match self.get_task_by_id(id) {
+            Some(t) => {
+                let stopped = t.stop_timer();
+                if stopped { t.touch(); }
+                stopped
+            },
+            None => { return Err(TaskNotFountError); },
+        };
+        self.dirty |= stopped;
+        Ok(stopped)
+    }
+
+    /// If the (just completed) task is recurring, adds its next occurrence
+    /// and returns its id. The completed task stays around as history and
+    /// stops recurring itself, the rule moves on to the new occurrence.
+    fn spawn_next_occurrence(&mut self, id: u32) -> Option<u32> {
+        let completed = self.tasks.iter().position(|t| t.id == id)?;
+        let completed_on = self.tasks[completed]
+            .completed_at
+            .map(|c| c.date_naive())
+            .unwrap_or(dates::today());
+        // a rule that runs past the last representable date just ends
+        let due = self.tasks[completed].recurrence.as_ref()?.next_due(self.tasks[completed].due, completed_on)?;
+        let recurrence = self.tasks[completed].recurrence.take()?;
+
+        let mut next = self.tasks[completed].clone();
+        next.id = self.allocate_id();
+        next.hash = self.generate_hash(next.id, &next.title);
+        next.status = self.workflow.initial();
+        next.completed_at = None;
+        next.previous = Some(id);
+        next.intervals.clear();
+        next.activity.clear();
+        next.conflicts.clear();
+        next.created_at = Some(Local::now());
+        next.updated_at = next.created_at;
+        next.due = Some(due);
+        // keep the same distance between the scheduled and due dates
+        if let (Some(scheduled), Some(old_due)) = (next.scheduled, self.tasks[completed].due) {
+            next.scheduled = scheduled.checked_add_signed(due - old_due).or(next.scheduled);
+        }
+        next.recurrence = Some(recurrence);
+
+        let next_id = next.id;
+        self.tasks.push(next);
+        Some(next_id)
+    }
+
+    /// The earlier, completed occurrences of a recurring task, most recent first.
+    pub fn previous_occurrences(&self, id: u32) -> Vec<&Task> {
+        let mut res = Vec::<&Task>::new();
+        let mut current = self.tasks.iter().find(|t| t.id == id).and_then(|t| t.previous);
+        while let Some(prev) = current {
+            match self.tasks.iter().find(|t| t.id == prev) {
+                Some(t) => {
+                    res.push(t);
+                    current = t.previous;
+                },
+                None => { break; },
+            };
+        }
+        res
+    }
+
+    pub fn change_task_description(&mut self, task_selector: TaskSelector, new_description: &str) -> Result<(), TaskNotFountError> {
+        let task: Option<&mut Task> = match task_selector {
+            TaskSelector::Title(title) => self.get_task_by_title(title),
+            TaskSelector::Id(id) => self.get_task_by_id(id),
+        };
+        if let Some(t) = task {
+            t.touch();
+            if t.description != new_description {
+                t.record(Activity::DescriptionChanged(t.description.clone(), new_description.to_owned()));
+            }
+            t.description = new_description.to_owned();
+        } else {
+            return Err(TaskNotFountError);
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn add_note(&mut self, task_selector: TaskSelector, text: &str) -> Result<(), TaskNotFountError> {
+        let task: Option<&mut Task> = match task_selector {
+            TaskSelector::Title(title) => self.get_task_by_title(title),
+            TaskSelector::Id(id) => self.get_task_by_id(id),
+        };
+        if let Some(t) = task {
+            t.touch();
+            t.record(Activity::Note(text.to_owned()));
+        } else {
+            return Err(TaskNotFountError);
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn change_task_recurrence(&mut self, task_selector: TaskSelector, new_recurrence: Option<Recurrence>) -> Result<(), TaskNotFountError> {
+        let task: Option<&mut Task> = match task_selector {
+            TaskSelector::Title(title) => self.get_task_by_title(title),
+            TaskSelector::Id(id) => self.get_task_by_id(id),
+        };
+        if let Some(t) = task {
+            t.touch();
+            t.recurrence = new_recurrence;
+        } else {
+            return Err(TaskNotFountError);
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn change_task_due(&mut self, task_selector: TaskSelector, new_due: Option<NaiveDate>) -> Result<(), TaskNotFountError> {
+        let task: Option<&mut Task> = match task_selector {
+            TaskSelector::Title(title) => self.get_task_by_title(title),
+            TaskSelector::Id(id) => self.get_task_by_id(id),
+        };
+        if let Some(t) = task {
+            t.touch();
+            t.due = new_due;
+        } else {
+            return Err(TaskNotFountError);
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn change_task_scheduled(&mut self, task_selector: TaskSelector, new_scheduled: Option<NaiveDate>) -> Result<(), TaskNotFountError> {
+        let task: Option<&mut Task> = match task_selector {
+            TaskSelector::Title(title) => self.get_task_by_title(title),
+            TaskSelector::Id(id) => self.get_task_by_id(id),
+        };
+        if let Some(t) = task {
+            t.touch();
+            t.scheduled = new_scheduled;
+        } else {
+            return Err(TaskNotFountError);
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn tag_task(&mut self, task_selector: TaskSelector, tags: &[String]) -> Result<(), TaskNotFountError> {
+        let task: Option<&mut Task> = match task_selector {
+            TaskSelector::Title(title) => self.get_task_by_title(title),
+            TaskSelector::Id(id) => self.get_task_by_id(id),
+        };
+        if let Some(t) = task {
+            t.touch();
+            for tag in tags { t.tags.insert(tag.to_owned()); }
+        } else {
+            return Err(TaskNotFountError);
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn untag_task(&mut self, task_selector: TaskSelector, tags: &[String]) -> Result<(), TaskNotFountError> {
+        let task: Option<&mut Task> = match task_selector {
+            TaskSelector::Title(title) => self.get_task_by_title(title),
+            TaskSelector::Id(id) => self.get_task_by_id(id),
+        };
+        if let Some(t) = task {
+            t.touch();
+            for tag in tags { t.tags.remove(tag); }
+        } else {
+            return Err(TaskNotFountError);
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn filter_task_status(&self, status: &Status) -> Vec<&Task> {
+        self.tasks
+            .iter()
+            .filter(|e| e.status == *status)
+            .collect()
+    }
+
+    pub fn filter_task_tags(&self, tags: &[String], tag_match: TagMatch) -> Vec<&Task> {
+        self.tasks
+            .iter()
+            .filter(|e| match tag_match {
+                TagMatch::Any => tags.iter().any(|t| e.has_tag(t)),
+                TagMatch::All => tags.iter().all(|t| e.has_tag(t)),
+            })
+            .collect()
+    }
+
+    pub fn log_tasks<W: Write>(&mut self, handle: &mut W, sort_by: SortBy) {
+        match sort_by {
+            SortBy::Id => self.tasks.sort_by_key(|e| e.id),
+            SortBy::Title => self.tasks.sort_by_key(|e| e.title.chars().nth(0)),
+            SortBy::Priority => self.tasks.sort_by_key(|e| e.priority),
+            SortBy::Status => {
+                let workflow = &self.workflow;
+                self.tasks.sort_by_key(|e| workflow.index(&e.status));
+            },
+            // tasks without a due date go last
+            SortBy::Due => self.tasks.sort_by_key(|e| (e.due.is_none(), e.due)),
+            // oldest first, so stale tasks come up on top
+            SortBy::Created => self.tasks.sort_by_key(|e| e.created_at),
+            SortBy::Updated => self.tasks.sort_by_key(|e| e.updated_at),
+            SortBy::None => {},
+        };
+        for t in self.tasks.iter() {
+            writeln!(handle, "{}", t).unwrap();
+        }
+    }
+
+    /// Tasks modified on or after the given day.
+    pub fn changed_since(&self, date: NaiveDate) -> Vec<&Task> {
+        let mut res: Vec<&Task> = self.tasks
+            .iter()
+            .filter(|t| t.updated_at.map(|u| u.date_naive() >= date).unwrap_or(false))
+            .collect();
+        res.sort_by_key(|t| std::cmp::Reverse(t.updated_at));
+        res
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{JsonStorage, MemoryStorage};
+    use crate::testdir::TestDir;
+
+    fn manager(content: &str) -> TaskManager {
+        let mut tm = TaskManager::new(Box::new(MemoryStorage::with_content(content)));
+        tm.load().unwrap();
+        tm
+    }
+
+    fn add(tm: &mut TaskManager, title: &str) -> u32 {
+        tm.new_task(title, "", Priority::new(0), Status::new("ToDo"))
+    }
+
+    #[test]
+    fn ids_come_from_the_counter_and_are_never_reused() {
+        let mut tm = manager("");
+        assert_eq!(add(&mut tm, "a"), 0);
+        let b = add(&mut tm, "b");
+        assert_eq!(b, 1);
+        tm.remove_task(TaskSelector::Id(b));
+        assert_eq!(add(&mut tm, "c"), 2);
+
+        tm.save().unwrap();
+        tm.load().unwrap();
+        assert_eq!(add(&mut tm, "d"), 3);
+    }
+
+    #[test]
+    fn hashes_are_unique_and_never_numeric() {
+        let mut tm = manager("");
+        for i in 0..200 {
+            add(&mut tm, &format!("task {}", i));
+        }
+        let hashes: HashSet<&str> = tm.tasks.iter().map(|t| t.hash()).collect();
+        assert_eq!(hashes.len(), 200);
+        assert!(hashes.iter().all(|h| h.parse::<u32>().is_err()));
+    }
+
+    #[test]
+    fn duplicate_ids_and_missing_hashes_are_repaired_on_load() {
+        let mut tm = manager(r#"{"version": 2, "next_id": 0, "projects": [{"name": "default", "tasks": [
+            {"id": 4, "title": "a", "description": "", "priority": 0, "status": "ToDo"},
+            {"id": 4, "title": "b", "description": "", "priority": 0, "status": "ToDo"}
+        ]}]}"#);
+        let ids: Vec<u32> = tm.tasks.iter().map(|t| t.id()).collect();
+        assert_eq!(ids, vec![4, 5]);
+        assert!(tm.tasks.iter().all(|t| !t.hash().is_empty()));
+        assert!(tm.is_dirty());
+        assert!(tm.take_warnings().iter().any(|w| w.contains("duplicate id")));
+        // the counter is past every id in the file
+        assert_eq!(add(&mut tm, "c"), 6);
+    }
+
+    #[test]
+    fn loading_an_untouched_file_leaves_nothing_to_save() {
+        let tm = manager(r#"{"version": 2, "next_id": 1, "active_project": "work", "projects": [
+            {"name": "default", "tasks": []},
+            {"name": "work", "tasks": [{"id": 0, "hash": "a3f9", "title": "a", "description": "", "priority": 0, "status": "ToDo"}]}
+        ]}"#);
+        assert_eq!(tm.project(), "work");
+        assert!(!tm.is_dirty());
+    }
+
+    #[test]
+    fn dependencies_block_until_done_and_cant_form_a_cycle() {
+        let mut tm = manager("");
+        let (a, b, c) = (add(&mut tm, "a"), add(&mut tm, "b"), add(&mut tm, "c"));
+        tm.add_dependency(b, a).unwrap();
+        tm.add_dependency(c, b).unwrap();
+        assert_eq!(tm.add_dependency(a, c), Err(DependencyError::Cycle));
+        assert_eq!(tm.add_dependency(a, a), Err(DependencyError::Cycle));
+        assert_eq!(tm.add_dependency(a, 7), Err(DependencyError::DependencyNotFound));
+
+        assert!(tm.tasks.iter().filter(|t| t.is_blocked()).map(|t| t.id()).eq([b, c]));
+        assert!(tm.next_tasks().iter().map(|t| t.id()).eq([a]));
+        tm.change_task_status(TaskSelector::Id(a), Status::new("Done")).unwrap();
+        assert!(tm.next_tasks().iter().map(|t| t.id()).eq([b]));
+        // a dependency on a removed task doesn't block anything
+        tm.remove_task(TaskSelector::Id(b));
+        assert!(tm.tasks.iter().all(|t| !t.is_blocked()));
+        assert!(tm.tasks.iter().any(|t| t.depends_on().contains(&b)));
+    }
+
+    #[test]
+    fn cycles_in_the_file_are_broken_on_load() {
+        let mut tm = manager(r#"{"version": 2, "next_id": 2, "projects": [{"name": "default", "tasks": [
+            {"id": 0, "hash": "a3f9", "title": "a", "description": "", "priority": 0, "status": "ToDo", "parent": 1, "depends_on": [1]},
+            {"id": 1, "hash": "b3f9", "title": "b", "description": "", "priority": 0, "status": "ToDo", "parent": 0, "depends_on": [0]}
+        ]}]}"#);
+        assert_eq!(tm.subtree(0).len(), 2);
+        assert!(tm.tasks.iter().any(|t| t.parent().is_none()));
+        assert!(tm.tasks.iter().any(|t| t.depends_on().is_empty()));
+        let warnings = tm.take_warnings();
+        assert!(warnings.iter().any(|w| w.contains("own ancestor")));
+        assert!(warnings.iter().any(|w| w.contains("depended on itself")));
+        assert!(tm.is_dirty());
+    }
+
+    #[test]
+    fn resolving_a_conflict_cant_close_a_cycle() {
+        let mut tm = manager(r#"{"version": 2, "next_id": 2, "projects": [{"name": "default", "tasks": [
+            {"id": 0, "hash": "a3f9", "title": "a", "description": "", "priority": 0, "status": "ToDo", "parent": 1},
+            {"id": 1, "hash": "b3f9", "title": "b", "description": "", "priority": 0, "status": "ToDo",
+             "conflicts": [{"field": "parent", "ours": null, "theirs": 0}]}
+        ]}]}"#);
+        assert_eq!(tm.resolve_conflict(1, Side::Theirs, None), Err(ConflictError::Cycle));
+        assert_eq!(tm.resolve_conflict(1, Side::Ours, None), Ok(()));
+        assert!(tm.tasks.iter().all(|t| t.conflicts().is_empty()));
+    }
+
+    #[test]
+    fn done_tasks_move_to_the_archive_file_and_back() {
+        let dir = TestDir::new();
+        let path = dir.join("tasks.json");
+        let open = || {
+            let mut tm = TaskManager::new(Box::new(JsonStorage::open(&path).unwrap()));
+            tm.load().unwrap();
+            tm
+        };
+        let mut tm = open();
+        let a = add(&mut tm, "water the plants");
+        add(&mut tm, "b");
+        tm.change_task_status(TaskSelector::Id(a), Status::new("Done")).unwrap();
+        assert_eq!(tm.archive_done().unwrap(), vec![a]);
+        tm.save().unwrap();
+        drop(tm);
+        assert!(Archive::path_for(&path).exists());
+
+        let mut tm = open();
+        assert_eq!(tm.tasks.len(), 1);
+        assert!(tm.search_archive("PLANTS").unwrap().iter().map(|t| t.task.id()).eq([a]));
+        assert_eq!(tm.restore_task(a).unwrap(), DEFAULT_PROJECT);
+        assert_eq!(tm.archived_task(a).err(), Some(ArchiveError::TaskNotFound));
+        assert_eq!(tm.tasks.len(), 2);
+    }
+
+    #[test]
+    fn tasks_done_for_long_are_archived_on_load() {
+        let mut tm = manager(r#"{"version": 2, "next_id": 2, "auto_archive_days": 7, "projects": [{"name": "default", "tasks": [
+            {"id": 0, "hash": "a3f9", "title": "a", "description": "", "priority": 0, "status": "Done", "completed_at": "2020-01-01T00:00:00+00:00"},
+            {"id": 1, "hash": "b3f9", "title": "b", "description": "", "priority": 0, "status": "Done"}
+        ]}]}"#);
+        assert!(tm.tasks.iter().map(|t| t.id()).eq([1]));
+        assert!(tm.archived_task(0).is_ok());
+        assert!(tm.take_warnings().iter().any(|w| w.starts_with("auto-archived 1 tasks")));
+        assert!(tm.is_dirty());
+    }
+
+    #[test]
+    fn scale_and_statuses_apply_to_every_project() {
+        let mut tm = manager("");
+        tm.new_task("a", "", Priority::new(2), Status::new("ToDo"));
+        tm.switch_project("other");
+        assert!(tm.set_statuses(vec![StatusDef::new("Backlog", None), StatusDef::new("Done", None)]).is_err());
+
+        tm.set_priority_scale(PriorityScale::numeric(9).unwrap()).unwrap();
+        tm.switch_project("default");
+        assert_eq!(tm.tasks[0].priority(), Priority::new(8));
+    }
+
+    #[test]
+    fn priority_changes_are_logged_with_the_scale_names() {
+        let mut tm = manager("");
+        let a = add(&mut tm, "a");
+        tm.change_task_priority(TaskSelector::Id(a), Priority::new(2)).unwrap();
+
+        let scale = tm.priority_scale().clone();
+        let log = tm.get_task_by_id(a).unwrap().log(&scale);
+        assert!(log.contains("priority: Low -> High"), "{}", log);
+    }
+
+    #[test]
+    fn starting_a_timer_that_stops_another_projects_cant_be_undone() {
+        let mut tm = manager("");
+        let a = add(&mut tm, "a");
+        tm.start_timer(a).unwrap();
+        tm.switch_project("other");
+        let b = add(&mut tm, "b");
+
+        tm.begin_operation("start");
+        assert_eq!(tm.start_timer(b).unwrap(), Some(a));
+        tm.end_operation();
+        assert!(tm.undo().is_err());
     }
 }
@@ -2,28 +2,81 @@
 
 use std::{
     io::{self, Write},
-    path::Path,
-    str::FromStr
+    str::FromStr,
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant},
 };
 use super::taskmanager::*;
 use super::queue::Queue;
+use super::dates::{parse_date, today};
+use super::recurrence::Recurrence;
+use super::workflow::{StatusDef, WorkflowError, MAX_STATUSES};
+use super::scale::{PriorityScale, ScaleError, MAX_LEVELS};
+use super::storage::{Storage, TaskVersion};
+use super::merge::{MergeReport, Side};
+use super::events::{self, Event};
+
+use chrono::NaiveDate;
 
 extern crate crossterm;
 use crossterm::terminal;
 
+// how long the task file has to stay unchanged before changes made to it
+// by other programs are picked up
+const FILE_SETTLE_TIME: Duration = Duration::from_millis(200);
+
 static HELP_MSG: &str =
 r#"
 <command> <arg1> <arg2> ...
+<id> can be a task's number or its short hash (e.g. a3f9 or #a3f9)
 
 List of commands:
 * help
-* list
+* list <optional:id|title|priority|status|due|created|updated>
+* changed <optional:date>   (tasks modified since the date, today by default)
 * add "<title>" "<optional:description>" <optional:priority> <optional:status>
+      [due:<date>] [scheduled:<date>] [every:<rule>] [parent:<id>] [+tag ...]
+  (dates: 2023-06-01, today, tomorrow, fri, +3d, +2w, +1m)
+  (rules: daily, weekly:mon,thu, monthly:15, after:3d)
+* history <id> <optional:date>   (every saved version of the task, or the one it had on that date)
 * remove <id>
-* description <id> <description>
-* priority <id> <new_priority>
+* description <id> "<description>"
+* priority <id> <new_priority>   (a level name or its number, 1 being the lowest)
 * status <id> <new_status>
-* exit
+* recur <id> <rule|none>
+* parent <id> <parent_id|none>
+* note <id> "<text>"
+* start <id>   (start tracking time, stops any other running timer)
+* stop <id>
+* depend <id> <dependency_id>
+* undepend <id> <dependency_id>
+* next   (ToDo tasks that aren't blocked by unfinished dependencies)
+* tag <id> <tag> ...
+* untag <id> <tag> ...
+* focus <tag> ... [all]   (narrow the kanban blocks, no tags to clear)
+* project <name>   (switch to the project, creating it if needed)
+* projects
+* move <id> <project>   (moves the task and its subtasks)
+* all   (tasks of every project)
+* archive <optional:id>   (archive a task, or every done task of the project)
+* archive search <optional:text>
+* archive show <id>
+* restore <id>   (move an archived task back to its project)
+* autoarchive <days|off>   (archive tasks done for longer than that on load)
+* restore-backup <optional:number>   (list the backups, or restore one; unsaved changes are lost)
+* scale <level> ...        (priority levels from lowest to highest, or e.g. 'scale 9' for 1-9)
+* workflow <status> ...    (e.g. workflow Backlog ToDo Doing "Review=In Review" Done)
+* allow <status> <to_status> ...|any   (restrict which statuses a task can move to)
+* save   (asks what to do if the file changed on disk since it was loaded)
+* reload   (drops unsaved changes)
+* merge   (merges the changes made to the file on disk, task by task)
+* resolve <optional:id> <ours|theirs> <optional:field>   (settle a task's merge conflicts, no id lists them)
+* sync <optional:remote>   (commit the task file, merge the remote's changes task by task and push)
+* passphrase   (asks for a new passphrase to encrypt the task file with, empty to stop encrypting it)
+* autosave <optional:command|off|<n>s|<n>m>   (save after every command, at most every n seconds/minutes, or only on 'save')
+* undo   (reverts the last change to the project's tasks, e.g. add, remove, status or priority)
+* redo
+* quit   (asks first if there are unsaved changes)
 "#;
 
 #[derive(Debug)]
@@ -34,16 +87,180 @@ struct ParseCommandError;
 enum Command {
     Help,
     Show(u32),
-    Add(String, String, Priority, Status),
+    Add(NewTask),
     Description(u32, String),
+    List(SortBy),
+    Changed(NaiveDate),
+    History(u32, Option<NaiveDate>),
     Remove(u32),
     Priority(u32, Priority),
     Status(u32, Status),
+    Recur(u32, Option<Recurrence>),
+    Parent(u32, Option<u32>),
+    Note(u32, String),
+    Start(u32),
+    Stop(u32),
+    Depend(u32, u32),
+    Undepend(u32, u32),
+    Next,
+    Tag(u32, Vec<String>),
+    Untag(u32, Vec<String>),
+    Focus(Option<(Vec<String>, bool)>),
+    Project(String),
+    Projects,
+    Move(u32, String),
+    All,
+    Archive(Option<u32>),
+    ArchiveSearch(String),
+    ArchiveShow(u32),
+    Restore(u32),
+    AutoArchive(Option<u32>),
+    RestoreBackup(Option<usize>),
+    Scale(PriorityScale),
+    Workflow(Vec<StatusDef>),
+    Allow(Status, Option<Vec<Status>>),
     Save,
+    Reload,
+    Merge,
+    Sync(Option<String>),
+    Passphrase,
+    Conflicts,
+    Resolve(u32, Side, Option<String>),
+    Autosave(Option<Autosave>),
+    Undo,
+    Redo,
     Quit,
     None,
 }
 
+impl Command {
+    // commands that only change the active project's tasks, those are
+    // recorded so they can be undone
+    fn undoable(&self) -> bool {
+        matches!(self,
+            Command::Add(_) | Command::Description(..) | Command::Remove(_) | Command::Priority(..)
+            | Command::Status(..) | Command::Recur(..) | Command::Parent(..) | Command::Note(..)
+            | Command::Start(_) | Command::Stop(_) | Command::Depend(..) | Command::Undepend(..)
+            | Command::Tag(..) | Command::Untag(..) | Command::Resolve(..))
+    }
+}
+
+#[derive(Default)]
+struct NewTask {
+    title: String,
+    description: String,
+    priority: Priority,
+    // the workflow's first status if not given
+    status: Option<Status>,
+    due: Option<NaiveDate>,
+    scheduled: Option<NaiveDate>,
+    tags: Vec<String>,
+    parent: Option<u32>,
+    recurrence: Option<Recurrence>,
+}
+
+// a question asked through the prompt, answered by the next input line
+enum Pending {
+    CompleteParent(u32),
+    RemoveParent(u32),
+    Quit,
+    // the save file changed on disk since it was loaded
+    ExternalChange,
+}
+
+impl Pending {
+    fn question(&self) -> String {
+        match self {
+            Pending::CompleteParent(id) => format!("all subtasks of task {} are done, mark it as done too? (y/n)", id),
+            Pending::RemoveParent(id) => format!("task {} has subtasks: (r)emove them too, (k)eep them or (c)ancel?", id),
+            Pending::Quit => "there are unsaved changes: (s)ave and quit, (q)uit anyway or (c)ancel?".to_string(),
+            Pending::ExternalChange => "the file changed on disk: (r)eload it dropping your changes, (m)erge, (o)verwrite it or (c)ancel?".to_string(),
+        }
+    }
+
+    // how answering it is shown when it's undone
+    fn label(&self) -> Option<String> {
+        match self {
+            Pending::CompleteParent(id) => Some(format!("complete task {}", id)),
+            Pending::RemoveParent(id) => Some(format!("remove task {}", id)),
+            Pending::Quit | Pending::ExternalChange => None,
+        }
+    }
+}
+
+fn status_error(e: StatusError, id: u32) -> String {
+    match e {
+        StatusError::TaskNotFound => format!("could not find task with id '{}'...", id),
+        StatusError::UnknownStatus => "unknown status...".to_string(),
+        StatusError::NotAllowed(from, to) => format!("can't move task '{}' from '{}' to '{}'...", id, from, to),
+    }
+}
+
+fn scale_error(e: ScaleError) -> String {
+    match e {
+        ScaleError::Size => format!("a priority scale needs 1 to {} levels...", MAX_LEVELS),
+        ScaleError::DuplicateLevel(l) => format!("priority level '{}' appears more than once...", l),
+    }
+}
+
+fn archive_error(e: ArchiveError, id: u32) -> String {
+    match e {
+        ArchiveError::Unavailable => "the archive file couldn't be loaded...".to_string(),
+        ArchiveError::TaskNotFound => format!("could not find task with id '{}'...", id),
+    }
+}
+
+// what a merge did besides merging, `theirs` tells where the other side is
+fn merge_report_lines(report: &MergeReport, theirs: &str) -> Vec<String> {
+    let mut lines: Vec<String> = report.conflicts
+        .iter()
+        .map(|c| match (&c.ours, &c.theirs) {
+            (Some(_), Some(_)) => format!("  task {} was changed on both sides ({}), resolve it with 'resolve {} ours|theirs'",
+                c.id, c.fields.join(", "), c.id),
+            (Some(_), None) => format!("  task {} was removed {} but changed here, kept it until it's resolved", c.id, theirs),
+            (None, Some(t)) => format!("  task {} ('{}') was changed {} but removed here, kept it until it's resolved", c.id, t.title(), theirs),
+            (None, None) => format!("  task {}", c.id),
+        })
+        .collect();
+    for (old, new) in report.renumbered.iter() {
+        lines.push(format!("  your task {} is now task {}, its id was taken {}", old, new, theirs));
+    }
+    lines
+}
+
+fn conflict_error(e: ConflictError, id: u32) -> String {
+    match e {
+        ConflictError::TaskNotFound => format!("could not find task with id '{}'...", id),
+        ConflictError::NoConflict => format!("task '{}' has no such conflict...", id),
+        ConflictError::Cycle => format!("taking their value would make task '{}' its own ancestor or dependency...", id),
+    }
+}
+
+fn history_error(e: HistoryError, what: &str) -> String {
+    match e {
+        HistoryError::Empty => format!("nothing to {}...", what),
+        HistoryError::OtherProject(p) => format!("the last change to {} was made in project '{}', switch to it first...", what, p),
+    }
+}
+
+fn workflow_error(e: WorkflowError) -> String {
+    match e {
+        WorkflowError::Empty => "the workflow needs at least one status...".to_string(),
+        WorkflowError::TooMany => format!("the workflow can have at most {} statuses...", MAX_STATUSES),
+        WorkflowError::DuplicateStatus(s) => format!("status '{}' appears more than once...", s),
+        WorkflowError::UnknownStatus(s) => format!("status '{}' is not in the workflow (or still in use)...", s),
+    }
+}
+
+// tags are written as '+tag', the '+' is optional outside of 'add'
+fn parse_tag(token: &str) -> Result<String, String> {
+    let tag = token.trim_start_matches('+');
+    if tag.is_empty() || tag.contains(char::is_whitespace) {
+        return Err(format!("Invalid tag '{}'...", token));
+    }
+    Ok(tag.to_owned())
+}
+
 impl FromStr for Command {
     type Err = ParseCommandError;
 
@@ -60,11 +277,14 @@ struct Block {
     height: usize,
     title: String,
     content: Vec<String>,
+    // lines drawn in reverse video
+    highlighted: Vec<usize>,
 }
 
 impl Block {
+    // blocks are at least as big as their borders, however small the terminal
     fn new(x: usize, y: usize, width: usize, height: usize, title: &str) -> Block {
-        Block { x, y, width, height, title: title.to_string(), content: Vec::new() }
+        Block { x, y, width: width.max(2), height: height.max(2), title: title.to_string(), content: Vec::new(), highlighted: Vec::new() }
     }
 
     fn draw<W: Write>(&self, handle: &mut W) -> Result<(), io::Error> {
@@ -72,24 +292,27 @@ impl Block {
         write!(handle, "\x1B[{};{}H", self.y, self.x)?;
 
         // Draw the top border
-        let mut formatted_title = self.title.clone();
-        formatted_title.push_str("-".repeat(self.width - self.title.len() - 1).as_str());
+        let mut formatted_title: String = self.title.chars().take(self.width - 2).collect();
+        formatted_title.push_str("-".repeat(self.width - formatted_title.chars().count() - 1).as_str());
         write!(handle, "-{:<width$}", &formatted_title, width = self.width - 2)?;
 
         for i in 1..self.height-1 {
             let gap = self.width - 2;
             write!(handle, "\x1B[{};{}H", self.y + i, self.x)?;
+            let (on, off) = if self.highlighted.contains(&(i-1)) { ("\x1B[7m", "\x1B[0m") } else { ("", "") };
             write!(
                 handle,
-                "|{:<width$}|",
+                "|{}{:<width$}{}|",
+                on,
                 match self.content.get(i-1) {
                     Some(t) => {
-                        let mut res = t.clone().trim().to_string();
+                        let mut res = t.clone().trim_end().to_string();
                         res.truncate(self.width - 2);
                         res
                     },
                     None => { String::from("") },
                 },
+                off,
                 width = self.width - 2
             )?;
         }
@@ -102,44 +325,57 @@ impl Block {
     }
 }
 
-pub struct TUI<'a> {
-    pub tm: TaskManager<'a>,
+#[allow(clippy::upper_case_acronyms)]
+pub struct TUI {
+    pub tm: TaskManager,
     quit: bool,
     err_hist: Queue<String>,
     cmd_hist: Queue<String>,
     log_buf: Vec<String>,
+    // tags the kanban blocks are narrowed to, and whether all of them must match
+    focus: Option<(Vec<String>, bool)>,
+    pending: Option<Pending>,
+    // when the task file was last saved, for interval autosaves
+    last_save: Instant,
+    // one block per workflow status
+    kanban: Vec<Block>,
+    // Errors, Commands and Show
     blocks: Vec<Block>,
     width: usize,
     height: usize,
 }
-impl<'a> TUI<'a> {
-    pub fn new(save_file: Box<&'a Path>) -> TUI {
+impl TUI {
+    pub fn new(storage: Box<dyn Storage>) -> TUI {
         let (cols, rows) = terminal::size().unwrap();
-        let queue_cap = (rows*1/6) as usize - 2;
+        let queue_cap = (rows/6) as usize - 2;
         let mut err_hist = Queue::<String>::new(queue_cap);
-        TUI {
-            tm: match TaskManager::new(save_file.clone()) {
-                Ok(tm) => { tm },
-                Err(e) => {
-                    err_hist.push(e);
-                    TaskManager::default(save_file)
-                },
-            },
+        let mut tm = TaskManager::new(storage);
+        let load_error = tm.load().err();
+        for w in tm.take_warnings() { err_hist.push(w); }
+        if let Some(e) = &load_error { err_hist.push(e.clone()); }
+        let mut tui = TUI {
+            tm,
             quit: false,
             err_hist,
             cmd_hist: Queue::new(queue_cap),
             log_buf: Vec::new(),
+            focus: None,
+            pending: None,
+            last_save: Instant::now(),
+            kanban: Vec::new(),
             blocks: vec![
-                Block::new(0,                   1,                     (cols*1/3-1) as usize, (rows*1/2)   as usize, "ToDo"),
-                Block::new((cols*1/3) as usize, 1,                     (cols*1/3)   as usize, (rows*1/2)   as usize, "Doing"),
-                Block::new((cols*2/3) as usize, 1,                     (cols*1/3)   as usize, (rows*1/2)   as usize, "Done"),
-                Block::new(0,                   (rows*1/2+1) as usize, (cols*1/2-1) as usize, (rows*1/6)   as usize, "Errors"),
-                Block::new((cols*1/2) as usize, (rows*1/2+1) as usize, (cols*1/2-1) as usize, (rows*1/6)   as usize, "Commands"),
-                Block::new(0,                   (rows*2/3)   as usize, (cols)       as usize, (rows*1/3-1) as usize, "Show"),
+                Block::new(0,                   (rows/2+1) as usize, (cols/2-1) as usize, (rows/6)   as usize, "Errors"),
+                Block::new((cols/2) as usize, (rows/2+1) as usize, (cols/2-1) as usize, (rows/6)   as usize, "Commands"),
+                Block::new(0,                   (rows*2/3)   as usize, (cols)       as usize, (rows/3-1) as usize, "Show"),
             ],
             width: cols as usize,
             height: rows as usize,
+        };
+        // too long for the errors block, and the location matters for fixing the file
+        if let Some(e) = load_error {
+            let _ = tui.show(&format!("{}\n(autosave is off until 'save', which replaces the file and keeps its content as a backup)", e));
         }
+        tui
     }
 
     fn draw_ui<W: Write>(&self, handle: &mut W) -> Result<(), io::Error> {
@@ -153,13 +389,16 @@ impl<'a> TUI<'a> {
         // Move the cursor to the top-left corner
         handle.write_all(b"\x1B[H")?;
 
-        for block in self.blocks.iter() {
+        for block in self.kanban.iter().chain(self.blocks.iter()) {
             block.draw(handle)?;
         }
 
         // Draw Prompt
         handle.write_all(b"\n")?;
-        handle.write_all(b"> ")?;
+        if let Some(pending) = &self.pending {
+            write!(handle, "{} ", pending.question())?;
+        }
+        write!(handle, "[{}]{} > ", self.tm.project(), if self.tm.is_dirty() { " (unsaved)" } else { "" })?;
 
         handle.flush()?;
 
@@ -170,85 +409,361 @@ impl<'a> TUI<'a> {
         let stdout = io::stdout();
         let mut handle = stdout.lock();
 
+        // input and changes to the task file arrive on the same channel, the
+        // timeout lets interval autosaves happen while waiting for input
+        let (tx, rx) = mpsc::channel();
+        let resume_input = events::read_input(tx.clone());
+        let _watcher = match self.tm.path().map(|p| events::watch(p, tx.clone())) {
+            Some(Err(e)) => {
+                self.err_hist.push(format!("can't watch the task file, changes made to it won't show up: {}...", e));
+                None
+            },
+            Some(Ok(w)) => Some(w),
+            None => None,
+        };
+        drop(tx);
+
+        let mut redraw = true;
+        // when the task file last changed, it's only looked at once writing
+        // it has settled (a program writing in place truncates it first)
+        let mut changed_at: Option<Instant> = None;
         while !self.quit {
-            let mut input = String::new();
+            if redraw {
+                self.layout_kanban();
+                self.blocks[0].content = self.err_hist.clone_elements();
+                self.blocks[1].content = self.cmd_hist.clone_elements();
+                self.blocks[2].content = self.log_buf.clone();
 
-            self.blocks[0].content = self.tm
-                .filter_task_status(Status::ToDo)
-                .iter()
-                .map(|e| format!("{}", e))
-                .collect();
-            self.blocks[1].content = self.tm
-                .filter_task_status(Status::Doing)
-                .iter()
-                .map(|e| format!("{}", e))
-                .collect();
-            self.blocks[2].content = self.tm
-                .filter_task_status(Status::Done)
-                .iter()
-                .map(|e| format!("{}", e))
-                .collect();
-            self.blocks[3].content = self.err_hist.clone_elements();
-            self.blocks[4].content = self.cmd_hist.clone_elements();
-            self.blocks[5].content = self.log_buf.clone();
-
-            match self.draw_ui(&mut handle) {
-                Ok(_) => { },
-                Err(e) => { println!("{}", e); },
+                match self.draw_ui(&mut handle) {
+                    Ok(_) => { },
+                    Err(e) => { println!("{}", e); },
+                }
             }
 
-            std::io::stdin().read_line(&mut input).expect("couldn't read stdin");
-            input = input.trim_end().to_owned();
-            self.cmd_hist.push(input.to_owned());
-
-            match self.process_input(&input) {
-                Ok(cmd) => {
-                    match self.execute_command(cmd) {
-                        Ok(_) => { },
-                        Err(e) => { self.err_hist.push(e) },
-                    };
+            let timeout = match changed_at {
+                Some(_) => FILE_SETTLE_TIME,
+                None => Duration::from_secs(1),
+            };
+            redraw = match rx.recv_timeout(timeout) {
+                Ok(Event::Input(input)) => {
+                    self.handle_input(input);
+                    let _ = resume_input.send(());
+                    true
+                },
+                Ok(Event::FileChanged) => {
+                    changed_at = Some(Instant::now());
+                    false
+                },
+                Ok(Event::Closed) | Err(RecvTimeoutError::Disconnected) => {
+                    self.quit = true;
+                    false
+                },
+                Err(RecvTimeoutError::Timeout) => {
+                    let dirty = self.tm.is_dirty();
+                    self.autosave();
+                    dirty != self.tm.is_dirty() || self.pending.is_some()
                 },
-                Err(e) => { self.err_hist.push(e); },
             };
+            if changed_at.is_some_and(|t| t.elapsed() >= FILE_SETTLE_TIME) {
+                changed_at = None;
+                redraw |= self.file_changed();
+            }
         }
     }
 
+    fn handle_input(&mut self, input: String) {
+        self.cmd_hist.push(input.to_owned());
+
+        // a pending question consumes the input as its answer
+        if let Some(pending) = self.pending.take() {
+            if let Some(label) = pending.label() { self.tm.begin_operation(&label); }
+            match self.answer(pending, &input) {
+                Ok(_) => { self.autosave(); },
+                Err(e) => { self.err_hist.push(e) },
+            };
+            self.tm.end_operation();
+            return;
+        }
+
+        match self.process_input(&input) {
+            Ok(cmd) => {
+                if cmd.undoable() { self.tm.begin_operation(&input); }
+                match self.execute_command(cmd) {
+                    Ok(_) => { self.autosave(); },
+                    Err(e) => { self.err_hist.push(e) },
+                };
+                self.tm.end_operation();
+            },
+            Err(e) => { self.err_hist.push(e); },
+        };
+        for w in self.tm.take_warnings() { self.err_hist.push(w); }
+    }
+
+    // picks up changes another program made to the task file, returns
+    // whether anything needs redrawing
+    fn file_changed(&mut self) -> bool {
+        // our own saves show up here too, they leave nothing to pick up
+        if !self.tm.has_external_changes() { return false; }
+        if self.tm.is_dirty() {
+            // a single write tends to come as several events
+            let notice = "the task file changed on disk, 'reload' or 'merge' to pick up the changes...".to_string();
+            if self.err_hist.last() == Some(&notice) { return false; }
+            self.err_hist.push(notice);
+            return true;
+        }
+        match self.tm.load() {
+            Ok(_) => { self.err_hist.push("reloaded, the task file changed on disk".to_string()); },
+            Err(e) => { self.err_hist.push(e); },
+        };
+        for w in self.tm.take_warnings() { self.err_hist.push(w); }
+        true
+    }
+
+    // saves on its own, according to the task file's autosave setting
+    fn autosave(&mut self) {
+        // saving would replace a file that couldn't be loaded, that's left to 'save'
+        if !self.tm.is_dirty() || self.quit || self.tm.load_failed() { return; }
+        let due = match self.tm.autosave() {
+            Autosave::EveryCommand => true,
+            Autosave::Interval(secs) => self.last_save.elapsed().as_secs() >= secs,
+            Autosave::Off => false,
+        };
+        if due && self.tm.has_external_changes() {
+            if self.pending.is_none() { self.pending = Some(Pending::ExternalChange); }
+        } else if due {
+            match self.tm.save() {
+                Ok(_) => { self.last_save = Instant::now(); },
+                Err(e) => { self.err_hist.push(format!("autosave failed: {}...", e)); },
+            };
+        }
+    }
+
+    fn answer(&mut self, pending: Pending, input: &str) -> Result<(), String> {
+        let input = input.trim().to_lowercase();
+        match pending {
+            Pending::CompleteParent(id) => {
+                if input == "y" || input == "yes" {
+                    let done = self.tm.workflow().done();
+                    self.execute_command(Command::Status(id, done))?;
+                }
+            },
+            Pending::ExternalChange => {
+                match input.as_str() {
+                    "r" => { self.execute_command(Command::Reload)?; },
+                    "m" => { self.execute_command(Command::Merge)?; },
+                    "o" => {
+                        self.tm.overwrite().map_err(|e| format!("{}", e))?;
+                        self.last_save = Instant::now();
+                    },
+                    _ => { },
+                };
+            },
+            Pending::RemoveParent(id) => {
+                match input.as_str() {
+                    "r" => { self.tm.remove_task_and_children(id) },
+                    "k" => { self.tm.remove_task(TaskSelector::Id(id)) },
+                    _ => { },
+                };
+            },
+            Pending::Quit => {
+                match input.as_str() {
+                    "s" => {
+                        self.execute_command(Command::Save)?;
+                        // unless saving has to ask about changes on disk first
+                        self.quit = self.pending.is_none();
+                    },
+                    "q" => { self.quit = true; },
+                    _ => { },
+                };
+            },
+        };
+        Ok(())
+    }
+
+    // splits the top half of the screen into one block per workflow status
+    fn layout_kanban(&mut self) {
+        let statuses = self.tm.workflow().statuses();
+        let n = statuses.len();
+        let suffix = match &self.focus {
+            Some((tags, all)) => format!(" {}{}",
+                tags.iter().map(|t| format!("+{}", t)).collect::<Vec<String>>().join(" "),
+                if *all { " (all)" } else { "" }),
+            None => String::new(),
+        };
+        self.kanban = statuses
+            .iter()
+            .enumerate()
+            .map(|(i, status)| {
+                let mut block = Block::new(
+                    i * self.width / n, 1, (self.width / n).saturating_sub(1), self.height / 2,
+                    &format!("{}{}", self.tm.workflow().display_name(status), suffix));
+                let tasks = self.kanban_content(status);
+                // tasks with merge conflicts stand out until they're resolved
+                block.highlighted = tasks
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, t)| !t.conflicts().is_empty())
+                    .map(|(i, _)| i)
+                    .collect();
+                block.content = tasks.iter().map(|t| format!("{}", t)).collect();
+                block
+            })
+            .collect();
+    }
+
+    // tasks with the given status, narrowed down to the focused tags if any
+    fn kanban_content(&self, status: &Status) -> Vec<&Task> {
+        let focused: Option<Vec<u32>> = self.focus
+            .as_ref()
+            .map(|(tags, all)| self.tm
+                .filter_task_tags(tags, if *all { TagMatch::All } else { TagMatch::Any })
+                .iter()
+                .map(|t| t.id())
+                .collect());
+        self.tm
+            .filter_task_status(status)
+            .iter()
+            .filter(|e| match &focused {
+                Some(ids) => ids.contains(&e.id()),
+                None => true,
+            })
+            .copied()
+            .collect()
+    }
+
+    // puts the text in the Show block, wrapping lines that don't fit
+    fn show(&mut self, text: &str) -> Result<(), String> {
+        let width = self.blocks
+            .last()
+            .ok_or("couldn't find 'Show' ui block...".to_string())?
+            .width - 2;
+        let mut lines = Vec::<String>::new();
+        for line in text.split('\n') {
+            let chars: Vec<char> = line.chars().collect();
+            for chunk in chars.chunks(width) {
+                lines.push(chunk.iter().collect());
+            }
+        }
+        self.log_buf = lines
+            .into_iter()
+            .filter(|e| !e.trim().is_empty())
+            .collect();
+        Ok(())
+    }
+
     fn execute_command(&mut self, cmd: Command) -> Result<(), String> {
         match cmd {
             Command::Help => { println!("{}", HELP_MSG) },
             Command::Show(id) => {
+                let scale = self.tm.priority_scale().clone();
                 let task = self.tm
                     .get_task_by_id(id)
                     .ok_or(format!("could not find task with id '{}'...", id))?;
-                let mut buffer = format!("{}", task.log());
-                let mut ccount = 0;
-                let mut indices = Vec::<usize>::new();
-                let mut i = 0;
-                for c in buffer.chars() {
-                    if c == '\n' {
-                        ccount = 0;
-                    } else { ccount += 1; }
-                    if ccount >= self.blocks
-                        .last()
-                        .ok_or(format!("couldn't find 'Log' ui block..."))?
-                        .width - 2
-                    {
-                        indices.push(i);
+                let mut buffer = task.log(&scale);
+                let previous = self.tm.previous_occurrences(id);
+                if !previous.is_empty() {
+                    buffer.push_str("\nprevious occurrences:");
+                    for t in previous.iter() {
+                        buffer.push_str(&format!("\n{} (completed {})", t,
+                            t.completed_at().map(|c| c.format("%Y-%m-%d").to_string()).unwrap_or_default()));
                     }
-                    i += 1;
                 }
-                for j in indices {
-                    buffer.insert(j, '\n');
+                let subtree = self.tm.subtree(id);
+                if subtree.len() > 1 {
+                    buffer.push_str("\nsubtasks:");
+                    for (depth, t) in subtree.iter().skip(1) {
+                        buffer.push_str(&format!("\n{}{}", "    ".repeat(*depth), t));
+                    }
                 }
-                self.log_buf = buffer
-                    .split('\n')
-                    .map(|e| e.to_string())
-                    .filter(|e| *e != "".to_string())
+                self.show(&buffer)?;
+            },
+            Command::Add(new_task) => {
+                let status = new_task.status.unwrap_or(self.tm.workflow().initial());
+                let id = self.tm.new_task(&new_task.title, &new_task.description, new_task.priority, status);
+                if let Some(parent) = new_task.parent {
+                    if self.tm.set_task_parent(id, Some(parent)).is_err() {
+                        self.tm.remove_task(TaskSelector::Id(id));
+                        return Err(format!("could not find task with id '{}'...", parent));
+                    }
+                }
+                self.tm
+                    .change_task_due(TaskSelector::Id(id), new_task.due)
+                    .ok().ok_or(format!("could not find task with id '{}'...", id))?;
+                self.tm
+                    .change_task_scheduled(TaskSelector::Id(id), new_task.scheduled)
+                    .ok().ok_or(format!("could not find task with id '{}'...", id))?;
+                self.tm
+                    .tag_task(TaskSelector::Id(id), &new_task.tags)
+                    .ok().ok_or(format!("could not find task with id '{}'...", id))?;
+                self.tm
+                    .change_task_recurrence(TaskSelector::Id(id), new_task.recurrence)
+                    .ok().ok_or(format!("could not find task with id '{}'...", id))?;
+            },
+            Command::Recur(id, recurrence) => {
+                self.tm
+                    .change_task_recurrence(TaskSelector::Id(id), recurrence)
+                    .ok().ok_or(format!("could not find task with id '{}'...", id))?;
+            },
+            Command::Description(id, description) => {
+                self.tm
+                    .change_task_description(TaskSelector::Id(id), &description)
+                    .ok().ok_or(format!("could not find task with id '{}'...", id))?;
+            },
+            Command::List(sort_by) => {
+                let mut buffer = Vec::<u8>::new();
+                self.tm.log_tasks(&mut buffer, sort_by);
+                self.show(&String::from_utf8_lossy(&buffer))?;
+            },
+            Command::History(id, None) => {
+                let versions: Vec<String> = self.tm
+                    .task_history(id)?
+                    .iter()
+                    .map(|v| format!("{}  {}", v.at.format("%Y-%m-%d %H:%M"), match &v.task {
+                        Some(t) => format!("{}", t),
+                        None => "removed".to_string(),
+                    }))
                     .collect();
+                if versions.is_empty() {
+                    self.show(&format!("no history for task {}", id))?;
+                } else {
+                    self.show(&format!("history of task {}:\n{}", id, versions.join("\n")))?;
+                }
+            },
+            Command::History(id, Some(date)) => {
+                // the last version saved by the end of that day
+                let history = self.tm.task_history(id)?;
+                match history.iter().rev().find(|v| v.at.date_naive() <= date) {
+                    Some(TaskVersion { at, task: Some(task) }) => {
+                        self.show(&format!("task {} as of {} (saved {}):\n{}", id, date, at.format("%Y-%m-%d %H:%M"), task.log(self.tm.priority_scale())))?;
+                    },
+                    Some(TaskVersion { at, task: None }) => {
+                        self.show(&format!("task {} was removed on {}", id, at.format("%Y-%m-%d %H:%M")))?;
+                    },
+                    None => { self.show(&format!("task {} didn't exist on {}", id, date))?; },
+                };
+            },
+            Command::Changed(date) => {
+                let changed: Vec<String> = self.tm
+                    .changed_since(date)
+                    .iter()
+                    .map(|t| format!("{}  {}", t
+                        .updated_at()
+                        .map(|u| u.format("%Y-%m-%d %H:%M").to_string())
+                        .unwrap_or_default(), t))
+                    .collect();
+                if changed.is_empty() {
+                    self.show(&format!("nothing changed since {}", date))?;
+                } else {
+                    self.show(&format!("changed since {}:\n{}", date, changed.join("\n")))?;
+                }
+            },
+            Command::Remove(id) => {
+                if self.tm.children(id).is_empty() {
+                    self.tm.remove_task(TaskSelector::Id(id));
+                } else {
+                    self.pending = Some(Pending::RemoveParent(id));
+                }
             },
-            Command::Add(title, description, priority, status) => { self.tm.new_task(&title, &description, priority, status) },
-            Command::Description(id, description) => { }
-            Command::Remove(id) => { self.tm.remove_task(TaskSelector::Id(id)) },
             Command::Priority(id, priority) => {
                 self.tm
                     .change_task_priority(TaskSelector::Id(id), priority)
@@ -257,21 +772,291 @@ impl<'a> TUI<'a> {
             Command::Status(id, status) => {
                 self.tm
                     .change_task_status(TaskSelector::Id(id), status)
+                    .map_err(|e| status_error(e, id))?;
+                if let Some(parent) = self.tm.completable_parent(id) {
+                    self.pending = Some(Pending::CompleteParent(parent));
+                }
+            },
+            Command::Parent(id, parent) => {
+                self.tm
+                    .set_task_parent(id, parent)
+                    .map_err(|e| match e {
+                        ParentError::TaskNotFound => format!("could not find task with id '{}'...", id),
+                        ParentError::ParentNotFound => format!("could not find task with id '{}'...", parent.unwrap_or(id)),
+                        ParentError::Cycle => format!("task '{}' can't be a subtask of its own subtask...", id),
+                    })?;
+            },
+            Command::Note(id, text) => {
+                self.tm
+                    .add_note(TaskSelector::Id(id), &text)
                     .ok().ok_or(format!("could not find task with id '{}'...", id))?;
             },
+            Command::Start(id) => {
+                let stopped = self.tm
+                    .start_timer(id)
+                    .ok().ok_or(format!("could not find task with id '{}'...", id))?;
+                if let Some(stopped) = stopped {
+                    self.cmd_hist.push(format!("(stopped timer on task {})", stopped));
+                }
+            },
+            Command::Stop(id) => {
+                let stopped = self.tm
+                    .stop_timer(id)
+                    .ok().ok_or(format!("could not find task with id '{}'...", id))?;
+                if !stopped {
+                    return Err(format!("no timer running on task '{}'...", id));
+                }
+            },
+            Command::Depend(id, dependency) => {
+                self.tm
+                    .add_dependency(id, dependency)
+                    .map_err(|e| match e {
+                        DependencyError::TaskNotFound => format!("could not find task with id '{}'...", id),
+                        DependencyError::DependencyNotFound => format!("could not find task with id '{}'...", dependency),
+                        DependencyError::Cycle => format!("task '{}' already depends on task '{}'...", dependency, id),
+                    })?;
+            },
+            Command::Undepend(id, dependency) => {
+                self.tm
+                    .remove_dependency(id, dependency)
+                    .ok().ok_or(format!("could not find task with id '{}'...", id))?;
+            },
+            Command::Next => {
+                let next: Vec<String> = self.tm
+                    .next_tasks()
+                    .iter()
+                    .map(|t| format!("{}", t))
+                    .collect();
+                if next.is_empty() {
+                    self.show("nothing can be started right now")?;
+                } else {
+                    self.show(&format!("can be started now:\n{}", next.join("\n")))?;
+                }
+            },
+            Command::Tag(id, tags) => {
+                self.tm
+                    .tag_task(TaskSelector::Id(id), &tags)
+                    .ok().ok_or(format!("could not find task with id '{}'...", id))?;
+            },
+            Command::Untag(id, tags) => {
+                self.tm
+                    .untag_task(TaskSelector::Id(id), &tags)
+                    .ok().ok_or(format!("could not find task with id '{}'...", id))?;
+            },
+            Command::Focus(focus) => { self.focus = focus; },
+            Command::Project(name) => { self.tm.switch_project(&name); },
+            Command::Projects => {
+                let projects: Vec<String> = self.tm
+                    .projects()
+                    .iter()
+                    .map(|(name, count)| format!("{}{} ({} tasks)",
+                        if *name == self.tm.project() { "* " } else { "  " }, name, count))
+                    .collect();
+                self.show(&projects.join("\n"))?;
+            },
+            Command::Move(id, project) => {
+                self.tm
+                    .move_task(id, &project)
+                    .map_err(|e| match e {
+                        MoveError::TaskNotFound => format!("could not find task with id '{}'...", id),
+                        MoveError::ProjectNotFound => format!("could not find project '{}'...", project),
+                    })?;
+            },
+            Command::All => {
+                let mut buffer = String::new();
+                for (project, tasks) in self.tm.all_projects_tasks() {
+                    buffer.push_str(&format!("{}:\n", project));
+                    for t in tasks {
+                        buffer.push_str(&format!("    {} ({})\n", t,
+                            self.tm.workflow().display_name(t.status())));
+                    }
+                }
+                self.show(&buffer)?;
+            },
+            Command::Archive(Some(id)) => {
+                self.tm
+                    .archive_task(id)
+                    .map_err(|e| archive_error(e, id))?;
+            },
+            Command::Archive(None) => {
+                let ids = self.tm
+                    .archive_done()
+                    .map_err(|e| archive_error(e, 0))?;
+                self.show(&format!("archived {} tasks", ids.len()))?;
+            },
+            Command::ArchiveSearch(query) => {
+                let found = self.tm
+                    .search_archive(&query)
+                    .map_err(|e| archive_error(e, 0))?;
+                let lines: Vec<String> = found
+                    .iter()
+                    .map(|a| format!("{} [{}, archived {}]",
+                        a.task, a.project, a.archived_at.format("%Y-%m-%d")))
+                    .collect();
+                let buffer = if lines.is_empty() { "no archived tasks found".to_string() } else { lines.join("\n") };
+                self.show(&buffer)?;
+            },
+            Command::ArchiveShow(id) => {
+                let archived = self.tm
+                    .archived_task(id)
+                    .map_err(|e| archive_error(e, id))?;
+                let buffer = format!("archived from '{}' on {}\n{}",
+                    archived.project, archived.archived_at.format("%Y-%m-%d %H:%M"), archived.task.log(self.tm.priority_scale()));
+                self.show(&buffer)?;
+            },
+            Command::Restore(id) => {
+                let project = self.tm
+                    .restore_task(id)
+                    .map_err(|e| archive_error(e, id))?;
+                self.show(&format!("restored task {} to project '{}'", id, project))?;
+            },
+            Command::AutoArchive(days) => {
+                let count = self.tm
+                    .set_auto_archive(days)
+                    .map_err(|e| archive_error(e, 0))?;
+                if count > 0 {
+                    self.show(&format!("archived {} tasks", count))?;
+                }
+            },
+            Command::RestoreBackup(None) => {
+                let lines: Vec<String> = self.tm
+                    .backups()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, b)| format!("{}. {} ({} bytes)", i + 1, b.created.format("%Y-%m-%d %H:%M:%S"), b.size))
+                    .collect();
+                let buffer = if lines.is_empty() { "no backups yet".to_string() } else { lines.join("\n") };
+                self.show(&buffer)?;
+            },
+            Command::RestoreBackup(Some(n)) => {
+                let backups = self.tm.backups();
+                let chosen = backups
+                    .get(n.wrapping_sub(1))
+                    .ok_or(format!("could not find backup '{}'...", n))?;
+                self.tm.restore_backup(chosen)?;
+                for w in self.tm.take_warnings() { self.err_hist.push(w); }
+                self.show(&format!("restored backup from {}", chosen.created.format("%Y-%m-%d %H:%M:%S")))?;
+            },
+            Command::Scale(scale) => {
+                self.tm
+                    .set_priority_scale(scale)
+                    .map_err(scale_error)?;
+            },
+            Command::Workflow(statuses) => {
+                self.tm
+                    .set_statuses(statuses)
+                    .map_err(workflow_error)?;
+            },
+            Command::Allow(from, to) => {
+                self.tm
+                    .set_transitions(&from, to)
+                    .map_err(workflow_error)?;
+            },
             Command::Save => {
+                if self.tm.has_external_changes() {
+                    self.pending = Some(Pending::ExternalChange);
+                    return Ok(());
+                }
                 match self.tm.save() {
-                    Ok(_) => { },
+                    Ok(_) => { self.last_save = Instant::now(); },
                     Err(e) => { return Err(format!("{}", e)); },
                 };
             },
-            Command::Quit => { self.quit = true; },
+            Command::Reload => {
+                self.tm.load()?;
+                self.show("reloaded")?;
+            },
+            Command::Merge => {
+                let report = self.tm.merge_external()?;
+                let mut lines = vec![format!("merged the changes on disk, {} conflicts", report.conflicts.len())];
+                lines.extend(merge_report_lines(&report, "on disk"));
+                self.show(&lines.join("\n"))?;
+            },
+            Command::Autosave(None) => {
+                self.show(&format!("autosave: {}", self.tm.autosave()))?;
+            },
+            Command::Autosave(Some(autosave)) => { self.tm.set_autosave(autosave); },
+            Command::Conflicts => {
+                let conflicts: Vec<String> = self.tm
+                    .conflicted_tasks()
+                    .iter()
+                    .flat_map(|t| std::iter::once(format!("{}", t)).chain(t
+                        .conflicts()
+                        .iter()
+                        .map(|c| format!("    {}: ours {}, theirs {}", c.field, c.ours, c.theirs))))
+                    .collect();
+                if conflicts.is_empty() {
+                    self.show("no conflicts")?;
+                } else { self.show(&conflicts.join("\n"))?; }
+            },
+            Command::Resolve(id, side, field) => {
+                self.tm
+                    .resolve_conflict(id, side, field.as_deref())
+                    .map_err(|e| conflict_error(e, id))?;
+            },
+            Command::Sync(remote) => {
+                let report = self.tm.sync(remote.as_deref())?;
+                self.last_save = Instant::now();
+                let mut lines = vec![match &report.committed {
+                    Some(summary) => format!("committed: {}", summary),
+                    None => "nothing to commit".to_string(),
+                }];
+                if let Some(merged) = &report.merged {
+                    lines.push(format!("merged the remote's changes, {} conflicts", merged.conflicts.len()));
+                    lines.extend(merge_report_lines(merged, "on the remote"));
+                } else if report.pulled {
+                    lines.push("pulled the remote's changes".to_string());
+                }
+                if report.pushed { lines.push("pushed".to_string()); }
+                self.show(&lines.join("\n"))?;
+            },
+            Command::Passphrase => {
+                // the input thread waits while a command runs, so the
+                // terminal can be read from here
+                let read = |prompt: &str| rpassword::prompt_password(prompt)
+                    .map_err(|e| format!("could not read the passphrase: {}...", e));
+                let passphrase = read("new passphrase (empty to stop encrypting): ")?;
+                if read("repeat it: ")? != passphrase {
+                    return Err("the passphrases don't match, nothing changed...".to_string());
+                }
+                let passphrase = Some(passphrase).filter(|p| !p.is_empty());
+                let encrypted = passphrase.is_some();
+                self.tm.set_passphrase(passphrase.as_deref())?;
+                self.last_save = Instant::now();
+                self.cmd_hist.push(format!("saved {}, the backups too",
+                    if encrypted { "with the new passphrase" } else { "without encryption" }));
+            },
+            Command::Undo => {
+                let label = self.tm.undo().map_err(|e| history_error(e, "undo"))?;
+                self.cmd_hist.push(format!("undone: {}", label));
+            },
+            Command::Redo => {
+                let label = self.tm.redo().map_err(|e| history_error(e, "redo"))?;
+                self.cmd_hist.push(format!("redone: {}", label));
+            },
+            Command::Quit => {
+                if self.tm.is_dirty() {
+                    self.pending = Some(Pending::Quit);
+                } else { self.quit = true; }
+            },
             Command::None => { },
         };
 
         Ok(())
     }
 
+    // accepts either a numeric id or a short hash id (with or without '#')
+    fn parse_task_id(&self, token: Option<&String>) -> Result<u32, String> {
+        let token = token.ok_or("Missing <task_id> argument...".to_string())?;
+        match token.parse::<u32>() {
+            Ok(id) => Ok(id),
+            Err(_) => self.tm
+                .get_task_by_hash(token)
+                .map(|t| t.id())
+                .ok_or("Invalid <task_id> argument...".to_string()),
+        }
+    }
+
     fn process_input(&self, input: &str) -> Result<Command, String> {
         // tokenize input
         let mut tokens: Vec<String> = Vec::new();
@@ -294,100 +1079,412 @@ impl<'a> TUI<'a> {
             }
         }
 
-        tokens.retain(|e| *e != "");
+        tokens.retain(|e| !e.is_empty());
 
         let mut tokens = tokens.iter();
         match tokens.next() {
             Some(cmd) => {
                 match cmd.as_str() {
                     "help" => {
-                        if let Some(_) = tokens.next() {
-                            return Err(format!("Unexpected arguments for command '{}'...", cmd));
-                        } else { return Ok(Command::Help); }
+                        if tokens.next().is_some() {
+                            Err(format!("Unexpected arguments for command '{}'...", cmd))
+                        } else { Ok(Command::Help) }
                     },
                     "show" => {
-                        let id = tokens
-                            .next()
-                            .ok_or(format!("Missing <task_id> argument..."))?
-                            .parse::<u32>()
-                            .ok().ok_or(format!("Invalid <task_id> argument..."))?;
-                        return Ok(Command::Show(id));
+                        let id = self.parse_task_id(tokens.next())?;
+                        Ok(Command::Show(id))
                     },
                     "add" => {
-                        let title = tokens
+                        // options like due:<date> can appear anywhere after the command,
+                        // everything else is positional
+                        let mut new_task = NewTask::default();
+                        let mut args = Vec::<&String>::new();
+                        for t in tokens {
+                            if let Some(d) = t.strip_prefix("due:") {
+                                new_task.due = Some(parse_date(d, today())
+                                    .ok().ok_or(format!("Invalid due date '{}'...", d))?);
+                            } else if let Some(d) = t.strip_prefix("scheduled:") {
+                                new_task.scheduled = Some(parse_date(d, today())
+                                    .ok().ok_or(format!("Invalid scheduled date '{}'...", d))?);
+                            } else if let Some(r) = t.strip_prefix("every:") {
+                                new_task.recurrence = Some(Recurrence::from_str(r)
+                                    .ok().ok_or(format!("Invalid recurrence '{}'...", r))?);
+                            } else if let Some(p) = t.strip_prefix("parent:") {
+                                new_task.parent = Some(self.parse_task_id(Some(&p.to_owned()))?);
+                            } else if t.starts_with('+') {
+                                new_task.tags.push(parse_tag(t)?);
+                            } else {
+                                args.push(t);
+                            }
+                        }
+                        let mut args = args.into_iter();
+
+                        new_task.title = args
                             .next()
-                            .ok_or(format!("Missing <title> argument..."))?
-                            .trim_matches('"');
-                        let description = match tokens.next() {
-                            Some(s) => { s.trim_matches('"') },
-                            None => { "" },
+                            .ok_or("Missing <title> argument...".to_string())?
+                            .trim_matches('"')
+                            .to_owned();
+                        new_task.description = match args.next() {
+                            Some(s) => { s.trim_matches('"').to_owned() },
+                            None => { String::new() },
                         };
-                        let priority = match tokens.next() {
+                        new_task.priority = match args.next() {
                             Some(p) => {
-                                Priority::from_str(p)
-                                    .ok()
-                                    .ok_or(format!("Invalid priority argument..."))?
+                                self.tm
+                                    .priority_scale()
+                                    .resolve(p)
+                                    .ok_or("Invalid priority argument...".to_string())?
                             },
                             None => { Priority::default() },
                         };
-                        let status = match tokens.next() {
+                        new_task.status = match args.next() {
                             Some(s) => {
-                                Status::from_str(s)
-                                    .ok()
-                                    .ok_or(format!("Invalid status argument..."))?
+                                Some(self.tm
+                                    .workflow()
+                                    .resolve(s)
+                                    .ok_or("Invalid status argument...".to_string())?)
                             },
-                            None => { Status::default() },
+                            None => { None },
                         };
 
-                        return Ok(Command::Add((*title).to_owned(), (*description).to_owned(), priority, status));
+                        Ok(Command::Add(new_task))
+                    },
+                    "description" => {
+                        let id = self.parse_task_id(tokens.next())?;
+                        let description = tokens
+                            .map(|t| t.trim_matches('"'))
+                            .collect::<Vec<&str>>()
+                            .join(" ");
+                        Ok(Command::Description(id, description))
+                    },
+                    "list" => {
+                        let sort_by = match tokens.next() {
+                            Some(s) => {
+                                SortBy::from_str(s)
+                                    .ok()
+                                    .ok_or("Invalid <sort_by> argument...".to_string())?
+                            },
+                            None => { SortBy::Id },
+                        };
+                        Ok(Command::List(sort_by))
+                    },
+                    "changed" => {
+                        let date = match tokens.next() {
+                            Some(d) => {
+                                parse_date(d, today())
+                                    .ok()
+                                    .ok_or("Invalid <date> argument...".to_string())?
+                            },
+                            None => { today() },
+                        };
+                        Ok(Command::Changed(date))
+                    },
+                    "history" => {
+                        let id = self.parse_task_id(tokens.next())?;
+                        let date = match tokens.next() {
+                            Some(d) => Some(parse_date(d, today())
+                                .ok()
+                                .ok_or("Invalid <date> argument...".to_string())?),
+                            None => None,
+                        };
+                        Ok(Command::History(id, date))
                     },
-                    "description" => { return Err(format!("Command 'description' not implemented yet...")); },
                     "remove" => {
-                        let id = tokens
-                            .next()
-                            .ok_or(format!("Missing <task_id> argument..."))?
-                            .parse::<u32>()
-                            .ok().ok_or(format!("Invalid <task_id> argument..."))?;
-                        return Ok(Command::Remove(id));
+                        let id = self.parse_task_id(tokens.next())?;
+                        Ok(Command::Remove(id))
                     },
                     "priority" => {
+                        let id = self.parse_task_id(tokens.next())?;
+                        let priority = self.tm
+                            .priority_scale()
+                            .resolve(tokens
+                                .next()
+                                .ok_or("Missing <new_priority> argument...".to_string())?)
+                            .ok_or("Invalid <new_priority> argument...".to_string())?;
+                        Ok(Command::Priority(id, priority))
+                    },
+                    "project" => {
+                        let name = tokens
+                            .next()
+                            .ok_or("Missing <name> argument...".to_string())?
+                            .trim_matches('"');
+                        if name.is_empty() {
+                            return Err("Invalid <name> argument...".to_string());
+                        }
+                        Ok(Command::Project(name.to_owned()))
+                    },
+                    "projects" | "all" => {
+                        if tokens.next().is_some() {
+                            return Err(format!("Unexpected arguments for command '{}'...", cmd));
+                        }
+                        if cmd == "projects" {
+                            Ok(Command::Projects)
+                        } else { Ok(Command::All) }
+                    },
+                    "move" => {
+                        let id = self.parse_task_id(tokens.next())?;
+                        let project = tokens
+                            .next()
+                            .ok_or("Missing <project> argument...".to_string())?
+                            .trim_matches('"');
+                        Ok(Command::Move(id, project.to_owned()))
+                    },
+                    "archive" => {
+                        match tokens.next().map(|t| t.as_str()) {
+                            None => { Ok(Command::Archive(None)) },
+                            Some("search") => {
+                                let query: Vec<String> = tokens
+                                    .map(|t| t.trim_matches('"').to_owned())
+                                    .collect();
+                                Ok(Command::ArchiveSearch(query.join(" ")))
+                            },
+                            Some("show") => {
+                                let id = tokens
+                                    .next()
+                                    .ok_or("Missing <task_id> argument...".to_string())?
+                                    .parse::<u32>()
+                                    .ok().ok_or("Invalid <task_id> argument...".to_string())?;
+                                Ok(Command::ArchiveShow(id))
+                            },
+                            Some(id) => {
+                                let id = self.parse_task_id(Some(&id.to_owned()))?;
+                                Ok(Command::Archive(Some(id)))
+                            },
+                        }
+                    },
+                    "restore" => {
                         let id = tokens
                             .next()
-                            .ok_or(format!("Missing <task_id> argument..."))?
+                            .ok_or("Missing <task_id> argument...".to_string())?
                             .parse::<u32>()
-                            .ok().ok_or(format!("Invalid <task_id> argument..."))?;
-                        let priority = Priority::from_str(tokens
-                                .next()
-                                .ok_or(format!("Missing <new_priority> argument..."))?
-                            ).ok().ok_or(format!("Invalid <new_priority> argument..."))?;
-                        return Ok(Command::Priority(id, priority));
+                            .ok().ok_or("Invalid <task_id> argument...".to_string())?;
+                        Ok(Command::Restore(id))
                     },
-                    "status" => {
-                        let id = tokens
+                    "autoarchive" => {
+                        let days = tokens
                             .next()
-                            .ok_or(format!("Missing <task_id> argument..."))?
+                            .ok_or("Missing <days> argument...".to_string())?;
+                        if days == "off" {
+                            return Ok(Command::AutoArchive(None));
+                        }
+                        let days = days
                             .parse::<u32>()
-                            .ok().ok_or(format!("Invalid <task_id> argument..."))?;
-                        let status = Status::from_str(tokens
+                            .ok().ok_or("Invalid <days> argument...".to_string())?;
+                        Ok(Command::AutoArchive(Some(days)))
+                    },
+                    "restore-backup" => {
+                        let n = match tokens.next() {
+                            Some(n) => Some(n
+                                .parse::<usize>()
+                                .ok().ok_or("Invalid <number> argument...".to_string())?),
+                            None => None,
+                        };
+                        Ok(Command::RestoreBackup(n))
+                    },
+                    "scale" => {
+                        let levels: Vec<String> = tokens
+                            .map(|t| t.trim_matches('"').to_owned())
+                            .collect();
+                        // a single number means a plain numeric scale, e.g. 'scale 9' for 1-9
+                        let scale = match levels.first().map(|l| l.parse::<usize>()) {
+                            Some(Ok(n)) if levels.len() == 1 => PriorityScale::numeric(n),
+                            _ => PriorityScale::new(levels),
+                        };
+                        Ok(Command::Scale(scale.map_err(scale_error)?))
+                    },
+                    "status" => {
+                        let id = self.parse_task_id(tokens.next())?;
+                        let status = self.tm
+                            .workflow()
+                            .resolve(tokens
                                 .next()
-                                .ok_or(format!("Missing <new_status> argument..."))?
-                            ).ok().ok_or(format!("Invalid <new_status> argument..."))?;
-                        return Ok(Command::Status(id, status));
+                                .ok_or("Missing <new_status> argument...".to_string())?)
+                            .ok_or("Invalid <new_status> argument...".to_string())?;
+                        Ok(Command::Status(id, status))
+                    },
+                    "workflow" => {
+                        // <name> or "<name>=<display name>"
+                        let statuses = tokens
+                            .map(|t| {
+                                let t = t.trim_matches('"');
+                                let (name, display) = match t.split_once('=') {
+                                    Some((name, display)) => (name, Some(display)),
+                                    None => (t, None),
+                                };
+                                Status::from_str(name)
+                                    .ok().ok_or(format!("Invalid status name '{}'...", name))?;
+                                Ok(StatusDef::new(name, display))
+                            })
+                            .collect::<Result<Vec<StatusDef>, String>>()?;
+                        if statuses.is_empty() {
+                            return Err("Missing <status> arguments...".to_string());
+                        }
+                        Ok(Command::Workflow(statuses))
+                    },
+                    "allow" => {
+                        let from = tokens
+                            .next()
+                            .ok_or("Missing <from_status> argument...".to_string())?;
+                        let from = self.tm
+                            .workflow()
+                            .resolve(from)
+                            .ok_or(format!("Invalid status '{}'...", from))?;
+                        let to: Vec<&String> = tokens.collect();
+                        if to.is_empty() {
+                            return Err("Missing <to_status> arguments...".to_string());
+                        }
+                        // 'allow <status> any' lifts the restriction
+                        if to.len() == 1 && to[0] == "any" {
+                            return Ok(Command::Allow(from, None));
+                        }
+                        let to = to
+                            .iter()
+                            .map(|t| self.tm
+                                .workflow()
+                                .resolve(t)
+                                .ok_or(format!("Invalid status '{}'...", t)))
+                            .collect::<Result<Vec<Status>, String>>()?;
+                        Ok(Command::Allow(from, Some(to)))
+                    },
+                    "recur" => {
+                        let id = self.parse_task_id(tokens.next())?;
+                        let rule = tokens
+                            .next()
+                            .ok_or("Missing <rule> argument...".to_string())?;
+                        if rule == "none" {
+                            return Ok(Command::Recur(id, None));
+                        }
+                        let recurrence = Recurrence::from_str(rule)
+                            .ok().ok_or("Invalid <rule> argument...".to_string())?;
+                        Ok(Command::Recur(id, Some(recurrence)))
+                    },
+                    "parent" => {
+                        let id = self.parse_task_id(tokens.next())?;
+                        let parent = match tokens.next() {
+                            Some(p) if p == "none" => None,
+                            p => Some(self.parse_task_id(p)?),
+                        };
+                        Ok(Command::Parent(id, parent))
+                    },
+                    "note" => {
+                        let id = self.parse_task_id(tokens.next())?;
+                        let text = tokens
+                            .map(|t| t.trim_matches('"'))
+                            .collect::<Vec<&str>>()
+                            .join(" ");
+                        if text.is_empty() {
+                            return Err("Missing <text> argument...".to_string());
+                        }
+                        Ok(Command::Note(id, text))
+                    },
+                    "start" | "stop" => {
+                        let id = self.parse_task_id(tokens.next())?;
+                        if tokens.next().is_some() {
+                            return Err(format!("Unexpected arguments for command '{}'...", cmd));
+                        }
+                        if cmd == "start" {
+                            Ok(Command::Start(id))
+                        } else { Ok(Command::Stop(id)) }
+                    },
+                    "depend" | "undepend" => {
+                        let id = self.parse_task_id(tokens.next())?;
+                        let dependency = self.parse_task_id(tokens.next())?;
+                        if cmd == "depend" {
+                            Ok(Command::Depend(id, dependency))
+                        } else { Ok(Command::Undepend(id, dependency)) }
+                    },
+                    "next" => {
+                        if tokens.next().is_some() {
+                            Err(format!("Unexpected arguments for command '{}'...", cmd))
+                        } else { Ok(Command::Next) }
+                    },
+                    "tag" | "untag" => {
+                        let id = self.parse_task_id(tokens.next())?;
+                        let tags = tokens
+                            .map(|t| parse_tag(t))
+                            .collect::<Result<Vec<String>, String>>()?;
+                        if tags.is_empty() {
+                            return Err("Missing <tag> argument...".to_string());
+                        }
+                        if cmd == "tag" {
+                            Ok(Command::Tag(id, tags))
+                        } else { Ok(Command::Untag(id, tags)) }
+                    },
+                    "focus" => {
+                        let mut all = false;
+                        let mut tags = Vec::<String>::new();
+                        for t in tokens {
+                            if t == "all" { all = true; } else { tags.push(parse_tag(t)?); }
+                        }
+                        if tags.is_empty() {
+                            Ok(Command::Focus(None))
+                        } else { Ok(Command::Focus(Some((tags, all)))) }
                     },
                     "save" => {
-                        if let Some(_) = tokens.next() {
+                        if tokens.next().is_some() {
+                            Err(format!("Unexpected arguments for command '{}'...", cmd))
+                        } else { Ok(Command::Save) }
+                    },
+                    "reload" | "merge" => {
+                        if tokens.next().is_some() {
                             return Err(format!("Unexpected arguments for command '{}'...", cmd));
-                        } else { return Ok(Command::Save); }
+                        }
+                        if cmd == "reload" {
+                            Ok(Command::Reload)
+                        } else { Ok(Command::Merge) }
                     },
-                    "quit" => {
-                        if let Some(_) = tokens.next() {
+                    "resolve" => {
+                        if tokens.as_slice().is_empty() {
+                            return Ok(Command::Conflicts);
+                        }
+                        let id = self.parse_task_id(tokens.next())?;
+                        let side = match tokens.next().map(|s| s.as_str()) {
+                            Some("ours") => Side::Ours,
+                            Some("theirs") => Side::Theirs,
+                            Some(_) => { return Err("Invalid <side> argument, it's 'ours' or 'theirs'...".to_string()); },
+                            None => { return Err("Missing <side> argument...".to_string()); },
+                        };
+                        Ok(Command::Resolve(id, side, tokens.next().map(|f| f.to_owned())))
+                    },
+                    "sync" => {
+                        let remote = tokens.next().map(|r| r.to_owned());
+                        if tokens.next().is_some() {
+                            return Err(format!("Unexpected arguments for command '{}'...", cmd));
+                        }
+                        Ok(Command::Sync(remote))
+                    },
+                    "passphrase" => {
+                        if !tokens.as_slice().is_empty() {
                             return Err(format!("Unexpected arguments for command '{}'...", cmd));
-                        } else { return Ok(Command::Quit); }
+                        }
+                        Ok(Command::Passphrase)
                     },
-                    _ => { return Err(format!("Invalid command '{}'...", cmd)); },
+                    "autosave" => {
+                        let autosave = match tokens.next() {
+                            Some(mode) => Some(mode
+                                .parse::<Autosave>()
+                                .ok().ok_or("Invalid <mode> argument...".to_string())?),
+                            None => None,
+                        };
+                        Ok(Command::Autosave(autosave))
+                    },
+                    "undo" | "redo" => {
+                        if tokens.next().is_some() {
+                            return Err(format!("Unexpected arguments for command '{}'...", cmd));
+                        }
+                        if cmd == "undo" {
+                            Ok(Command::Undo)
+                        } else { Ok(Command::Redo) }
+                    },
+                    "quit" => {
+                        if tokens.next().is_some() {
+                            Err(format!("Unexpected arguments for command '{}'...", cmd))
+                        } else { Ok(Command::Quit) }
+                    },
+                    _ => { Err(format!("Invalid command '{}'...", cmd)) },
                 }
             },
-            None => { return Ok(Command::None); },
-        };
+            None => { Ok(Command::None) },
+        }
     }
 }
@@ -0,0 +1,34 @@
+use std::{
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// tests run in parallel, each directory gets its own number
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// An empty directory for a test's files, removed with everything in it
+/// when dropped.
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    pub fn new() -> TestDir {
+        let path = std::env::temp_dir().join(format!("taskman-test-{}-{}",
+            process::id(), NEXT.fetch_add(1, Ordering::SeqCst)));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TestDir { path }
+    }
+
+    pub fn path(&self) -> &Path { &self.path }
+
+    pub fn join(&self, name: &str) -> PathBuf { self.path.join(name) }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+
+use serde::{Serialize, Deserialize};
+
+use super::taskmanager::Status;
+
+// every status gets a kanban column, more wouldn't leave them any room
+pub const MAX_STATUSES: usize = 8;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct StatusDef {
+    name: String,
+    // shown as the kanban block's title, defaults to the name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    display: Option<String>,
+}
+
+impl StatusDef {
+    pub fn new(name: &str, display: Option<&str>) -> StatusDef {
+        StatusDef { name: name.to_owned(), display: display.map(|d| d.to_owned()) }
+    }
+}
+
+/// The statuses a task file uses, in kanban order. The first status is the
+/// one new tasks start in and the last one means the task is done.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Workflow {
+    statuses: Vec<StatusDef>,
+    // status name -> statuses it can move to, a missing entry allows any move
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    transitions: BTreeMap<String, Vec<String>>,
+    // the status a task moves to when work starts on it, defaults to
+    // 'Doing' if there is one, otherwise to the second status
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    active: Option<String>,
+}
+
+impl Default for Workflow {
+    fn default() -> Self {
+        Workflow {
+            statuses: vec![
+                StatusDef::new("ToDo", None),
+                StatusDef::new("Doing", None),
+                StatusDef::new("Done", None),
+            ],
+            transitions: BTreeMap::new(),
+            active: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum WorkflowError {
+    Empty,
+    TooMany,
+    DuplicateStatus(String),
+    UnknownStatus(String),
+}
+
+impl Workflow {
+    /// A workflow with the given statuses and no restrictions. Files with
+    /// more than `MAX_STATUSES` still load, they just get narrow columns.
+    pub fn new(statuses: Vec<StatusDef>) -> Result<Workflow, WorkflowError> {
+        if statuses.len() > MAX_STATUSES {
+            return Err(WorkflowError::TooMany);
+        }
+        let workflow = Workflow { statuses, transitions: BTreeMap::new(), active: None };
+        workflow.validate()?;
+        Ok(workflow)
+    }
+
+    pub fn validate(&self) -> Result<(), WorkflowError> {
+        if self.statuses.is_empty() {
+            return Err(WorkflowError::Empty);
+        }
+        for (i, s) in self.statuses.iter().enumerate() {
+            if self.statuses[..i].iter().any(|o| o.name.eq_ignore_ascii_case(&s.name)) {
+                return Err(WorkflowError::DuplicateStatus(s.name.clone()));
+            }
+        }
+        for (from, to) in self.transitions.iter() {
+            for name in std::iter::once(from).chain(to.iter()) {
+                if !self.contains(&Status::new(name)) {
+                    return Err(WorkflowError::UnknownStatus(name.clone()));
+                }
+            }
+        }
+        if let Some(active) = &self.active {
+            if !self.contains(&Status::new(active)) {
+                return Err(WorkflowError::UnknownStatus(active.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn statuses(&self) -> Vec<Status> {
+        self.statuses.iter().map(|s| Status::new(&s.name)).collect()
+    }
+
+    pub fn contains(&self, status: &Status) -> bool {
+        self.statuses.iter().any(|s| s.name == status.name())
+    }
+
+    pub fn initial(&self) -> Status {
+        Status::new(&self.statuses[0].name)
+    }
+
+    pub fn done(&self) -> Status {
+        Status::new(&self.statuses[self.statuses.len() - 1].name)
+    }
+
+    pub fn active(&self) -> Status {
+        if let Some(active) = &self.active {
+            return Status::new(active);
+        }
+        if let Some(doing) = self.statuses.iter().find(|s| s.name == "Doing") {
+            return Status::new(&doing.name);
+        }
+        Status::new(&self.statuses[1.min(self.statuses.len() - 1)].name)
+    }
+
+    /// Position of the status in the workflow, unknown statuses sort last.
+    pub fn index(&self, status: &Status) -> usize {
+        self.statuses
+            .iter()
+            .position(|s| s.name == status.name())
+            .unwrap_or(self.statuses.len())
+    }
+
+    pub fn display_name(&self, status: &Status) -> String {
+        match self.statuses.iter().find(|s| s.name == status.name()) {
+            Some(StatusDef { display: Some(display), .. }) => display.clone(),
+            _ => status.name().to_owned(),
+        }
+    }
+
+    /// Finds a status by name or display name, ignoring case.
+    pub fn resolve(&self, s: &str) -> Option<Status> {
+        self.statuses
+            .iter()
+            .find(|d| d.name.eq_ignore_ascii_case(s)
+                || d.display.as_ref().map(|d| d.eq_ignore_ascii_case(s)).unwrap_or(false))
+            .map(|d| Status::new(&d.name))
+    }
+
+    pub fn allows(&self, from: &Status, to: &Status) -> bool {
+        if from == to { return true; }
+        match self.transitions.get(from.name()) {
+            Some(allowed) => allowed.iter().any(|a| a == to.name()),
+            None => true,
+        }
+    }
+
+    /// Restricts the moves out of `from` to the given statuses, or lifts the
+    /// restriction when `to` is None.
+    pub fn set_transitions(&mut self, from: &Status, to: Option<Vec<Status>>) -> Result<(), WorkflowError> {
+        for s in std::iter::once(from).chain(to.iter().flatten()) {
+            if !self.contains(s) {
+                return Err(WorkflowError::UnknownStatus(s.name().to_owned()));
+            }
+        }
+        match to {
+            Some(to) => {
+                self.transitions.insert(
+                    from.name().to_owned(),
+                    to.iter().map(|s| s.name().to_owned()).collect());
+            },
+            None => { self.transitions.remove(from.name()); },
+        };
+        Ok(())
+    }
+
+    /// Keeps the transition rules that still make sense with the new statuses.
+    pub fn carry_over_transitions(&mut self, old: &Workflow) {
+        for (from, to) in old.transitions.iter() {
+            if !self.contains(&Status::new(from)) { continue; }
+            let to: Vec<String> = to
+                .iter()
+                .filter(|t| self.contains(&Status::new(t)))
+                .cloned()
+                .collect();
+            self.transitions.insert(from.clone(), to);
+        }
+        if let Some(active) = &old.active {
+            if self.contains(&Status::new(active)) {
+                self.active = Some(active.clone());
+            }
+        }
+    }
+}